@@ -0,0 +1,227 @@
+pub mod batch;
+pub mod client;
+pub mod permission;
+pub mod registry;
+
+pub use batch::Batch;
+pub use batch::BatchApiClient;
+pub use client::HyperApiClient;
+pub use permission::PermissionProvider;
+pub use registry::ApiInfo;
+pub use registry::ApiRegistry;
+
+use crate::http::Body;
+use crate::http::BoxBody;
+use crate::http::Format;
+use crate::http::HttpHandler;
+use crate::http::RawBytes;
+use crate::http::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::{Request, StatusCode};
+use permission::ResKeyChecker;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tihu::api::Response;
+use tihu::Api;
+use tihu::Handler;
+use tihu::SharedString;
+
+/// Serve an [`Api`] over HTTP, so client and server share one typed definition.
+///
+/// The request body, bounded by [`crate::http::BodyLimit`], is deserialized into `A::Input`
+/// and checked by `A::validate_input`, then passed to the handler. Its result is responded as
+/// a [`Response`], errors included, so the status is always `200 OK` and the outcome is
+/// carried by `code`.
+///
+/// The body is decoded according to its `Content-Type` and the response encoded according to
+/// the `Accept` header, as JSON, XML or MessagePack, see [`Format`]. JSON is the default.
+///
+/// When `A::require_res_key` returns a key, the caller must hold it according to the
+/// [`PermissionProvider`], otherwise [`ErrNo::NotAllowed`] is responded. Without a provider
+/// such Apis are never allowed.
+pub struct ApiHandler<A, H> {
+    namespace: Vec<SharedString>,
+    handler: H,
+    permission: Option<Arc<dyn ResKeyChecker>>,
+    phantom: PhantomData<fn() -> A>,
+}
+
+impl<A, H> ApiHandler<A, H>
+where
+    A: Api + 'static,
+    A::Input: DeserializeOwned + Send + 'static,
+    A::Output: Serialize + Send + 'static,
+    H: Handler<A::Input, Out = Result<A::Output, ErrNo>>,
+{
+    pub fn new(handler: H) -> ApiHandler<A, H> {
+        let namespace = A::namespace()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| SharedString::from(segment.to_string()))
+            .collect();
+        ApiHandler {
+            namespace: namespace,
+            handler: handler,
+            permission: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn permission<P>(mut self, provider: Arc<P>) -> ApiHandler<A, H>
+    where
+        P: PermissionProvider,
+    {
+        self.permission.replace(provider);
+        return self;
+    }
+
+    /// Check the resource key required by `A`, returning the error to respond if not allowed.
+    async fn check_permission(
+        &self,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Option<ErrNo>, anyhow::Error> {
+        let res_key = match A::require_res_key() {
+            Some(res_key) => res_key,
+            None => return Ok(None),
+        };
+        let permission = match self.permission.as_ref() {
+            Some(permission) => permission,
+            None => return Ok(Some(ErrNo::NotAllowed)),
+        };
+        match permission
+            .check(request, remote_addr, request_data, &res_key)
+            .await
+        {
+            Ok(true) => return Ok(None),
+            Ok(false) => return Ok(Some(ErrNo::NotAllowed)),
+            Err(err) => return err.downcast::<ErrNo>().map(Some),
+        }
+    }
+
+    async fn call(
+        &self,
+        format: Format,
+        body: &[u8],
+    ) -> Result<Response<A::Output>, anyhow::Error> {
+        let input: A::Input = match format.decode(body) {
+            Ok(input) => input,
+            Err(_) => return Ok(ErrNo::ParamFormatError.into()),
+        };
+        if let Err(err_msg) = A::validate_input(&input) {
+            return Ok(ErrNo::ParamInvalid(err_msg).into());
+        }
+        let response = match self.handler.handle(input).await {
+            Ok(output) => Response::success(Some(output)),
+            Err(err_no) => err_no.into(),
+        };
+        return Ok(response);
+    }
+}
+
+#[async_trait]
+impl<A, H> HttpHandler for ApiHandler<A, H>
+where
+    A: Api + 'static,
+    A::Input: DeserializeOwned + Send + 'static,
+    A::Output: Serialize + Send + 'static,
+    H: Handler<A::Input, Out = Result<A::Output, ErrNo>>,
+{
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        mut request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        _prefix: Option<&str>,
+    ) -> Result<hyper::Response<BoxBody>, anyhow::Error> {
+        let response_format = Format::from_accept(request.headers());
+        let request_format = Format::from_content_type(request.headers());
+        if let Some(err_no) = self
+            .check_permission(&request, remote_addr, request_data)
+            .await?
+        {
+            return response_format.response(StatusCode::OK, &Response::<()>::from(err_no));
+        }
+        let body = match request_data
+            .remove_or_get_body::<RawBytes>(&mut request, remote_addr)
+            .await
+        {
+            Ok(RawBytes(body)) => body,
+            Err(err) => {
+                let err_no = err.downcast::<ErrNo>()?;
+                return response_format.response(StatusCode::OK, &Response::<()>::from(err_no));
+            }
+        };
+        let response = self.call(request_format, &body).await?;
+        return response_format.response(StatusCode::OK, &response);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_api_handler() {
+    use crate::http::TestRequest;
+
+    struct Divide;
+
+    #[async_trait]
+    impl Api for Divide {
+        type Input = (i64, i64);
+        type Output = i64;
+        fn namespace() -> SharedString {
+            SharedString::from_static("math/divide")
+        }
+        fn validate_input(input: &(i64, i64)) -> Result<(), SharedString> {
+            if 0 == input.1 {
+                return Err(SharedString::from_static("除数不能为0"));
+            }
+            return Ok(());
+        }
+    }
+
+    let handler = ApiHandler::<Divide, _>::new(|(a, b): (i64, i64)| async move {
+        if a < 0 {
+            return Err(ErrNo::NotAllowed);
+        }
+        return Ok(a / b);
+    });
+    let send = |request: TestRequest| {
+        let handler = &handler;
+        async move {
+            let response = request.send(handler).await.unwrap();
+            // The outcome is carried by the code, never by the status.
+            assert_eq!(StatusCode::OK, response.status);
+            return response.api_response::<i64>().unwrap();
+        }
+    };
+
+    let response = send(TestRequest::post("/math/divide").json(&(7, 2))).await;
+    assert_eq!(0, response.code);
+    assert_eq!(Some(3), response.data);
+    let response = send(TestRequest::post("/math/divide").json(&(-7, 2))).await;
+    assert_eq!(ErrNo::NotAllowed.code(), response.code);
+    assert_eq!(None, response.data);
+
+    let response = send(
+        TestRequest::post("/math/divide")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body("[7,"),
+    )
+    .await;
+    assert_eq!(ErrNo::ParamFormatError.code(), response.code);
+    let response = send(TestRequest::post("/math/divide").json(&("7", 2))).await;
+    assert_eq!(ErrNo::ParamFormatError.code(), response.code);
+
+    let response = send(TestRequest::post("/math/divide").json(&(7, 0))).await;
+    let err_no = ErrNo::ParamInvalid(SharedString::from_static("除数不能为0"));
+    assert_eq!(err_no.code(), response.code);
+    assert_eq!(err_no.to_string(), response.message.as_ref());
+}
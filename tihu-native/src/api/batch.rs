@@ -0,0 +1,587 @@
+use crate::http::json_response;
+use crate::http::router::prefix_len;
+use crate::http::Body;
+use crate::http::BoxBody;
+use crate::http::HttpHandler;
+use crate::http::RawBytes;
+use crate::http::RequestData;
+use crate::http::{err_no_response, read_body};
+use crate::ErrNo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use hyper::header::{
+    HeaderValue, ACCEPT, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use tihu::api::Response as ApiResponse;
+use tihu::ApiClient;
+use tihu::SharedString;
+use tokio::sync::oneshot;
+
+/// Path of the batch endpoint below the wrapped handler by default.
+pub const DEFAULT_BATCH_PATH: &str = "/batch";
+/// Entries accepted in one batch by default.
+pub const DEFAULT_MAX_ENTRIES: usize = 64;
+/// Time [`BatchApiClient`] waits for more calls before sending a batch by default.
+pub const DEFAULT_WINDOW: Duration = Duration::from_millis(10);
+
+/// One call of a batch, `input` is the JSON input of the Api.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct BatchEntry {
+    pub namespace: SharedString,
+    pub input: serde_json::Value,
+}
+
+/// Serve batches of Api calls at a path below the wrapped handler, a [`crate::http::Router`] or
+/// [`super::ApiRegistry`] for example, other requests are passed to it unchanged.
+///
+/// The batch is a JSON array of [`BatchEntry`], each entry is dispatched to the wrapped
+/// handler as a request to its namespace, with the headers of the batch request except the
+/// body and encoding ones, and the JSON array of their [`tihu::api::Response`] is responded
+/// in the same order.
+pub struct Batch<H> {
+    handler: H,
+    path: SharedString,
+    max_entries: usize,
+    concurrency: usize,
+}
+
+impl<H> Batch<H>
+where
+    H: HttpHandler,
+{
+    /// Serve the batches at [`DEFAULT_BATCH_PATH`], running their entries one after another.
+    pub fn new(handler: H) -> Batch<H> {
+        Batch {
+            handler: handler,
+            path: SharedString::from_static(DEFAULT_BATCH_PATH),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            concurrency: 1,
+        }
+    }
+
+    pub fn path<P>(mut self, path: P) -> Batch<H>
+    where
+        P: Into<SharedString>,
+    {
+        self.path = path.into();
+        return self;
+    }
+
+    /// Reject batches of more entries with [`ErrNo::ParamInvalid`].
+    pub fn max_entries(mut self, max_entries: usize) -> Batch<H> {
+        self.max_entries = max_entries;
+        return self;
+    }
+
+    /// Run up to `concurrency` entries of a batch at once, the responses keep the order of
+    /// the entries.
+    pub fn concurrency(mut self, concurrency: usize) -> Batch<H> {
+        self.concurrency = concurrency.max(1);
+        return self;
+    }
+
+    async fn call(
+        &self,
+        parts: &hyper::http::request::Parts,
+        remote_addr: SocketAddr,
+        prefix: Option<&str>,
+        entry: BatchEntry,
+    ) -> ApiResponse<serde_json::Value> {
+        let uri = format!(
+            "{}/{}",
+            prefix.unwrap_or_default().trim_end_matches('/'),
+            entry.namespace.trim_start_matches('/')
+        );
+        let body = match serde_json::to_vec(&entry.input) {
+            Ok(body) => body,
+            Err(err) => return ErrNo::SerializeError(err).into(),
+        };
+        let mut request = match Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .body(Body::from(body))
+        {
+            Ok(request) => request,
+            Err(_) => return ErrNo::NoSuchApi.into(),
+        };
+        let headers = request.headers_mut();
+        headers.extend(parts.headers.clone());
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(CONTENT_ENCODING);
+        // Entry responses are read here, the batch response is the one to compress.
+        headers.remove(ACCEPT_ENCODING);
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        // Each entry is a request of its own, values extracted for one are not shared.
+        let mut request_data = RequestData::new();
+        let response = match self
+            .handler
+            .handle(request, remote_addr, &mut request_data, prefix)
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => match err.downcast::<ErrNo>() {
+                Ok(err_no) => return err_no.into(),
+                Err(err) => {
+                    log::error!("批量调用{}失败: {:?}", entry.namespace, err);
+                    return ErrNo::CommonError(SharedString::from_static("服务器内部错误")).into();
+                }
+            },
+        };
+        let body = match read_body(response.into_body()).await {
+            Ok(body) => body,
+            Err(err) => return ErrNo::ApiError(err).into(),
+        };
+        return serde_json::from_slice(&body)
+            .unwrap_or_else(|err| ErrNo::DeserializeError(err).into());
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for Batch<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        mut request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let path = request.uri().path();
+        let is_batch = Method::POST == request.method()
+            && path[prefix_len(path, prefix)..].trim_end_matches('/')
+                == self.path.trim_end_matches('/');
+        if !is_batch {
+            return self
+                .handler
+                .handle(request, remote_addr, request_data, prefix)
+                .await;
+        }
+        let body = match request_data
+            .remove_or_get_body::<RawBytes>(&mut request, remote_addr)
+            .await
+        {
+            Ok(RawBytes(body)) => body,
+            Err(err) => return err_no_response(err.downcast::<ErrNo>()?),
+        };
+        let entries: Vec<BatchEntry> = match serde_json::from_slice(&body) {
+            Ok(entries) => entries,
+            Err(_) => return err_no_response(ErrNo::ParamFormatError),
+        };
+        if self.max_entries < entries.len() {
+            let message = format!("批量调用最多{}个", self.max_entries);
+            return err_no_response(ErrNo::ParamInvalid(message.into()));
+        }
+        let (parts, _) = request.into_parts();
+        let parts = &parts;
+        let responses = futures::stream::iter(entries)
+            .map(|entry| self.call(parts, remote_addr, prefix, entry))
+            .buffered(self.concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        return json_response(StatusCode::OK, &responses);
+    }
+}
+
+type PendingCall = (SharedString, Bytes, oneshot::Sender<Result<Bytes, ErrNo>>);
+
+/// The calls of the batch not sent yet, the generation tells the window timer of a batch
+/// sent early from the one of the next batch.
+#[derive(Default)]
+struct PendingBatch {
+    generation: u64,
+    calls: Vec<PendingCall>,
+}
+
+impl PendingBatch {
+    fn take(&mut self) -> Vec<PendingCall> {
+        self.generation += 1;
+        return mem::take(&mut self.calls);
+    }
+}
+
+/// An [`ApiClient`] coalescing the calls made within a short window into one batch, sent
+/// through `client` to a [`Batch`] endpoint.
+///
+/// A batch is sent once the window since its first call elapses, or as soon as it is full.
+/// When the batch itself fails, each of its calls gets the failure response of the
+/// [`ErrNo`], with its code.
+pub struct BatchApiClient<C> {
+    client: Arc<C>,
+    namespace: SharedString,
+    window: Duration,
+    max_entries: usize,
+    pending: Arc<Mutex<PendingBatch>>,
+}
+
+impl<C> BatchApiClient<C>
+where
+    C: ApiClient<Error = ErrNo> + Sync + Send + 'static,
+    C::Output: Send,
+{
+    /// Send the batches to the `batch` namespace, the [`DEFAULT_BATCH_PATH`] of the server.
+    pub fn new(client: C) -> BatchApiClient<C> {
+        BatchApiClient {
+            client: Arc::new(client),
+            namespace: SharedString::from_static(DEFAULT_BATCH_PATH),
+            window: DEFAULT_WINDOW,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            pending: Default::default(),
+        }
+    }
+
+    /// Set the namespace of the batch endpoint, matching [`Batch::path`].
+    pub fn namespace<N>(mut self, namespace: N) -> BatchApiClient<C>
+    where
+        N: Into<SharedString>,
+    {
+        self.namespace = namespace.into();
+        return self;
+    }
+
+    pub fn window(mut self, window: Duration) -> BatchApiClient<C> {
+        self.window = window;
+        return self;
+    }
+
+    /// Send a batch as soon as it has `max_entries` calls, matching [`Batch::max_entries`].
+    pub fn max_entries(mut self, max_entries: usize) -> BatchApiClient<C> {
+        self.max_entries = max_entries.max(1);
+        return self;
+    }
+}
+
+/// Send the calls pending, unless their batch of `generation` was sent already.
+async fn send_pending<C>(
+    client: Arc<C>,
+    namespace: SharedString,
+    pending: Arc<Mutex<PendingBatch>>,
+    generation: u64,
+) where
+    C: ApiClient<Error = ErrNo> + Sync + Send + 'static,
+{
+    let calls = {
+        let mut pending = pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if generation != pending.generation {
+            return;
+        }
+        pending.take()
+    };
+    if !calls.is_empty() {
+        send_batch(client, namespace, calls).await;
+    }
+}
+
+async fn send_batch<C>(client: Arc<C>, namespace: SharedString, calls: Vec<PendingCall>)
+where
+    C: ApiClient<Error = ErrNo> + Sync + Send + 'static,
+{
+    let mut entries = Vec::with_capacity(calls.len());
+    let mut senders = Vec::with_capacity(calls.len());
+    for (api_namespace, input, sender) in calls {
+        match serde_json::from_slice(&input) {
+            Ok(input) => {
+                entries.push(BatchEntry {
+                    namespace: api_namespace,
+                    input: input,
+                });
+                senders.push(sender);
+            }
+            Err(err) => {
+                sender.send(Err(ErrNo::DeserializeError(err))).ok();
+            }
+        }
+    }
+    if entries.is_empty() {
+        return;
+    }
+    let result = async {
+        let input = serde_json::to_vec(&entries).map_err(ErrNo::SerializeError)?;
+        let output = client.request(&namespace, input.into()).await?;
+        let responses: Vec<serde_json::Value> =
+            serde_json::from_slice(output.as_ref()).map_err(ErrNo::DeserializeError)?;
+        if responses.len() != senders.len() {
+            return Err(ErrNo::CommonError(SharedString::from_static(
+                "批量调用返回的结果数量不符",
+            )));
+        }
+        return Ok(responses);
+    }
+    .await;
+    match result {
+        Ok(responses) => {
+            for (response, sender) in responses.into_iter().zip(senders) {
+                let response = serde_json::to_vec(&response)
+                    .map(Bytes::from)
+                    .map_err(ErrNo::SerializeError);
+                sender.send(response).ok();
+            }
+        }
+        Err(err_no) => {
+            // ErrNo is not Clone, the calls get its failure response instead, which keeps
+            // the code.
+            let response = serde_json::to_vec(&ApiResponse::<()>::from(err_no));
+            let response = match response {
+                Ok(response) => Bytes::from(response),
+                Err(err) => {
+                    log::error!("序列化批量调用的失败结果失败: {:?}", err);
+                    return;
+                }
+            };
+            for sender in senders {
+                sender.send(Ok(response.clone())).ok();
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<C> ApiClient for BatchApiClient<C>
+where
+    C: ApiClient<Error = ErrNo> + Sync + Send + 'static,
+    C::Output: Send,
+{
+    type Output = Bytes;
+    type Error = ErrNo;
+    async fn request(&self, namespace: &str, input: Bytes) -> Result<Bytes, ErrNo> {
+        let (sender, receiver) = oneshot::channel();
+        let (first, full) = {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            pending
+                .calls
+                .push((namespace.to_string().into(), input, sender));
+            if self.max_entries <= pending.calls.len() {
+                (None, Some(pending.take()))
+            } else if 1 == pending.calls.len() {
+                (Some(pending.generation), None)
+            } else {
+                (None, None)
+            }
+        };
+        // Sent from a task of its own, so the batch is not dropped with this call.
+        if let Some(calls) = full {
+            tokio::spawn(send_batch(
+                self.client.clone(),
+                self.namespace.clone(),
+                calls,
+            ));
+        }
+        if let Some(generation) = first {
+            let send = send_pending(
+                self.client.clone(),
+                self.namespace.clone(),
+                self.pending.clone(),
+                generation,
+            );
+            let window = self.window;
+            tokio::spawn(async move {
+                tokio::time::sleep(window).await;
+                send.await;
+            });
+        }
+        return receiver.await.unwrap_or_else(|_| {
+            Err(ErrNo::CommonError(SharedString::from_static(
+                "批量调用被取消",
+            )))
+        });
+    }
+}
+
+#[cfg(test)]
+struct Add;
+
+#[cfg(test)]
+#[async_trait]
+impl tihu::Api for Add {
+    type Input = (i64, i64);
+    type Output = i64;
+    fn namespace() -> SharedString {
+        SharedString::from_static("math/add")
+    }
+}
+
+/// Sends the requests to the handler in-process, counting them.
+#[cfg(test)]
+struct LocalClient {
+    handler: Batch<crate::http::Router>,
+    requests: std::sync::atomic::AtomicUsize,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ApiClient for LocalClient {
+    type Output = Bytes;
+    type Error = ErrNo;
+    async fn request(&self, namespace: &str, input: Bytes) -> Result<Bytes, ErrNo> {
+        self.requests
+            .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        let response =
+            crate::http::TestRequest::post(&format!("/{}", namespace.trim_start_matches('/')))
+                .body(input)
+                .send(&self.handler)
+                .await
+                .map_err(ErrNo::ApiError)?;
+        return Ok(response.body);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_batch() {
+    use crate::http::{Router, TestRequest};
+    use crate::ApiHandler;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tihu::Api;
+
+    let mut router = Router::new();
+    router
+        .register(ApiHandler::<Add, _>::new(|(a, b): (i64, i64)| async move {
+            if a < 0 {
+                return Err(ErrNo::ParamInvalid("a".into()));
+            }
+            Ok(a + b)
+        }))
+        .unwrap();
+    let batch = Batch::new(router).concurrency(2).max_entries(3);
+
+    let entries = serde_json::json!([
+        {"namespace": "math/add", "input": [1, 2]},
+        {"namespace": "math/add", "input": [-1, 2]},
+        {"namespace": "math/none", "input": null},
+    ]);
+    let response = TestRequest::post("/batch")
+        .json(&entries)
+        .send(&batch)
+        .await
+        .unwrap();
+    let responses: Vec<ApiResponse<i64>> = response.json().unwrap();
+    assert_eq!(3, responses.len());
+    assert_eq!(Some(3), responses[0].data);
+    assert_eq!(ErrNo::ParamInvalid("".into()).code(), responses[1].code);
+    assert_eq!(ErrNo::NoSuchApi.code(), responses[2].code);
+    let response = TestRequest::post("/batch")
+        .json(&[&entries[0], &entries[0], &entries[0], &entries[0]])
+        .send(&batch)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, response.status);
+
+    let client = BatchApiClient::new(LocalClient {
+        handler: batch,
+        requests: AtomicUsize::new(0),
+    })
+    .window(Duration::from_millis(20));
+    let (a, b, c) = tokio::join!(
+        Add.call(&client, &(1, 1)),
+        Add.call(&client, &(2, 2)),
+        Add.call(&client, &(-3, 3)),
+    );
+    assert_eq!(Some(2), a.unwrap().data);
+    assert_eq!(Some(4), b.unwrap().data);
+    assert_eq!(ErrNo::ParamInvalid("".into()).code(), c.unwrap().code);
+    assert_eq!(1, client.client.requests.load(Ordering::Acquire));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_batch_failure() {
+    use tihu::Api;
+
+    struct BusyClient;
+
+    #[async_trait]
+    impl ApiClient for BusyClient {
+        type Output = Bytes;
+        type Error = ErrNo;
+        async fn request(&self, _namespace: &str, _input: Bytes) -> Result<Bytes, ErrNo> {
+            return Err(ErrNo::ServiceBusy("batch".into()));
+        }
+    }
+
+    let client = BatchApiClient::new(BusyClient);
+    let (a, b) = tokio::join!(Add.call(&client, &(1, 1)), Add.call(&client, &(2, 2)));
+    for response in [a.unwrap(), b.unwrap()] {
+        assert_eq!(ErrNo::ServiceBusy("".into()).code(), response.code);
+        assert_eq!(None, response.data);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test(start_paused = true)]
+async fn test_batch_window() {
+    use crate::http::Router;
+    use crate::ApiHandler;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tihu::Api;
+    use tokio::time::Instant;
+
+    let mut router = Router::new();
+    router
+        .register(ApiHandler::<Add, _>::new(|(a, b): (i64, i64)| async move {
+            Ok(a + b)
+        }))
+        .unwrap();
+    let window = Duration::from_millis(100);
+    let client = BatchApiClient::new(LocalClient {
+        handler: Batch::new(router),
+        requests: AtomicUsize::new(0),
+    })
+    .window(window)
+    .max_entries(2);
+    let (a, b) = tokio::join!(Add.call(&client, &(1, 1)), Add.call(&client, &(2, 2)));
+    assert_eq!(Some(2), a.unwrap().data);
+    assert_eq!(Some(4), b.unwrap().data);
+    // The timer of the batch sent full must not send the next batch early.
+    tokio::time::sleep(window / 2).await;
+    let start = Instant::now();
+    assert_eq!(Some(6), Add.call(&client, &(3, 3)).await.unwrap().data);
+    assert_eq!(window, start.elapsed());
+    assert_eq!(2, client.client.requests.load(Ordering::Acquire));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_batch_compression() {
+    use crate::http::{Compression, Router, TestRequest};
+    use crate::ApiHandler;
+    use tihu::Api;
+
+    struct Repeat;
+
+    #[async_trait]
+    impl Api for Repeat {
+        type Input = usize;
+        type Output = String;
+        fn namespace() -> SharedString {
+            SharedString::from_static("text/repeat")
+        }
+    }
+
+    let mut router = Router::new();
+    router
+        .register(ApiHandler::<Repeat, _>::new(|len: usize| async move {
+            Ok("x".repeat(len))
+        }))
+        .unwrap();
+    let batch = Batch::new(Compression::new(router));
+    let response = TestRequest::post("/batch")
+        .header("accept-encoding", "gzip")
+        .json(&serde_json::json!([{"namespace": "text/repeat", "input": 4096}]))
+        .send(&batch)
+        .await
+        .unwrap();
+    let responses: Vec<ApiResponse<String>> = response.json().unwrap();
+    assert_eq!(Some(4096), responses[0].data.as_ref().map(String::len));
+}
@@ -0,0 +1,178 @@
+use crate::http::read_body;
+use crate::http::Body;
+use crate::ErrNo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{HeaderMap, Method, Request, Uri};
+use hyper_util::client::legacy::connect::{Connect, HttpConnector};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::time::Duration;
+use tihu::ApiClient;
+use tihu::SharedString;
+
+/// An [`ApiClient`] that POSTs the serialized input to `{base_url}/{namespace}` with hyper.
+///
+/// The response body is returned whatever the status is, since the server reports failures
+/// as a [`tihu::api::Response`] with a non-zero code.
+pub struct HyperApiClient<C = HttpConnector> {
+    base_url: SharedString,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
+    client: Client<C, Body>,
+}
+
+impl HyperApiClient {
+    pub fn new<U>(base_url: U) -> HyperApiClient
+    where
+        U: Into<SharedString>,
+    {
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        return HyperApiClient::with_client(base_url, client);
+    }
+}
+
+impl<C> HyperApiClient<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Create a client on a preconfigured hyper client, for example one with a TLS connector.
+    pub fn with_client<U>(base_url: U, client: Client<C, Body>) -> HyperApiClient<C>
+    where
+        U: Into<SharedString>,
+    {
+        HyperApiClient {
+            base_url: base_url.into(),
+            headers: HeaderMap::new(),
+            timeout: None,
+            client: client,
+        }
+    }
+
+    /// Add a header sent with every request, such as an auth token or the client id.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> HyperApiClient<C> {
+        self.headers.insert(name, value);
+        return self;
+    }
+
+    /// Limit the time of each request, including reading the response body.
+    pub fn timeout(mut self, timeout: Duration) -> HyperApiClient<C> {
+        self.timeout.replace(timeout);
+        return self;
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    async fn send(&self, uri: Uri, input: Bytes) -> Result<Bytes, ErrNo> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(input))
+            .map_err(|err| ErrNo::ApiError(err.into()))?;
+        request.headers_mut().extend(self.headers.clone());
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| ErrNo::ApiError(err.into()))?;
+        let body = read_body(Body::from(response.into_body()))
+            .await
+            .map_err(ErrNo::ApiError)?;
+        return Ok(body);
+    }
+}
+
+#[async_trait]
+impl<C> ApiClient for HyperApiClient<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    type Output = Bytes;
+    type Error = ErrNo;
+    async fn request(&self, namespace: &str, input: Bytes) -> Result<Bytes, ErrNo> {
+        let url = format!(
+            "{}/{}",
+            self.base_url.trim_end_matches('/'),
+            namespace.trim_start_matches('/')
+        );
+        let uri: Uri = url
+            .parse()
+            .map_err(|_| ErrNo::ConfigError(format!("接口地址\"{}\"不正确", url).into()))?;
+        match self.timeout {
+            Some(timeout) => {
+                return tokio::time::timeout(timeout, self.send(uri, input))
+                    .await
+                    .map_err(|_| ErrNo::Timeout(format!("调用接口{}", namespace).into()))?;
+            }
+            None => {
+                return self.send(uri, input).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_hyper_api_client() {
+    use crate::http::{Router, Server};
+    use crate::ApiHandler;
+    use tihu::Api;
+
+    struct Greet;
+
+    #[async_trait]
+    impl Api for Greet {
+        type Input = String;
+        type Output = String;
+        fn namespace() -> SharedString {
+            SharedString::from_static("test/greet")
+        }
+        fn validate_input(name: &String) -> Result<(), SharedString> {
+            if name.is_empty() {
+                return Err(SharedString::from_static("名称不能为空"));
+            }
+            return Ok(());
+        }
+    }
+
+    async fn serve_greet() -> std::net::SocketAddr {
+        let mut router = Router::new();
+        router
+            .register(ApiHandler::<Greet, _>::new(|name: String| async move {
+                if "slow" == name {
+                    // Never responds, so only the client timeout ends the call.
+                    std::future::pending::<()>().await;
+                }
+                Ok(format!("Hello, {}!", name))
+            }))
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::new(router).serve(listener, std::future::pending()));
+        return addr;
+    }
+
+    let addr = serve_greet().await;
+    let client = HyperApiClient::new(format!("http://{}/", addr))
+        .header(
+            HeaderName::from_static("x-client-id"),
+            HeaderValue::from_static("test"),
+        )
+        .timeout(Duration::from_millis(500));
+
+    let response = Greet.call(&client, &String::from("tihu")).await.unwrap();
+    assert_eq!(0, response.code);
+    assert_eq!(Some("Hello, tihu!"), response.data.as_deref());
+
+    let response = Greet.call(&client, &String::new()).await.unwrap();
+    assert_eq!(ErrNo::ParamInvalid("".into()).code(), response.code);
+
+    match Greet.call(&client, &String::from("slow")).await {
+        Err(tihu::ApiErr::CallError(ErrNo::Timeout(_))) => (),
+        other => panic!("expected timeout, got {:?}", other),
+    }
+}
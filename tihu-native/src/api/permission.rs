@@ -0,0 +1,110 @@
+use crate::http::Body;
+use crate::http::FromRequest;
+use crate::http::RequestData;
+use async_trait::async_trait;
+use hyper::Request;
+use std::net::SocketAddr;
+
+/// Answers whether a caller holds a resource key, as required by [`tihu::Api::require_res_key`].
+///
+/// The caller identity is extracted through [`RequestData`], so it is shared with authorizers
+/// and handlers of the same request.
+#[async_trait]
+pub trait PermissionProvider: Sync + Send + 'static {
+    type Identity: FromRequest;
+    async fn has_res_key(
+        &self,
+        identity: &Self::Identity,
+        res_key: &str,
+    ) -> Result<bool, anyhow::Error>;
+}
+
+/// Object safe form of [`PermissionProvider`], hiding the identity type.
+#[async_trait]
+pub(crate) trait ResKeyChecker: Sync + Send + 'static {
+    async fn check(
+        &self,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        res_key: &str,
+    ) -> Result<bool, anyhow::Error>;
+}
+
+#[async_trait]
+impl<P> ResKeyChecker for P
+where
+    P: PermissionProvider,
+{
+    async fn check(
+        &self,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        res_key: &str,
+    ) -> Result<bool, anyhow::Error> {
+        let identity = request_data
+            .try_get::<P::Identity>(request, remote_addr)
+            .await?;
+        return self.has_res_key(identity, res_key).await;
+    }
+}
+
+/// The caller named by the `x-user` header.
+#[cfg(test)]
+pub(crate) struct User(pub String);
+
+#[cfg(test)]
+#[async_trait]
+impl FromRequest for User {
+    async fn try_extract(
+        request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let user = request
+            .headers()
+            .get("x-user")
+            .and_then(|user| user.to_str().ok())
+            .ok_or(crate::ErrNo::LoginRequired)?;
+        return Ok(User(user.to_string()));
+    }
+}
+
+/// `admin` holds every resource key, other users hold the keys named after them.
+#[cfg(test)]
+pub(crate) struct UserPermission;
+
+#[cfg(test)]
+#[async_trait]
+impl PermissionProvider for UserPermission {
+    type Identity = User;
+    async fn has_res_key(&self, user: &User, res_key: &str) -> Result<bool, anyhow::Error> {
+        return Ok("admin" == user.0 || user.0 == res_key);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_res_key_checker() {
+    use crate::ErrNo;
+
+    async fn check(user: Option<&str>, res_key: &str) -> Result<bool, anyhow::Error> {
+        let mut request = Request::builder();
+        if let Some(user) = user {
+            request = request.header("x-user", user);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        let checker: &dyn ResKeyChecker = &UserPermission;
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        return checker
+            .check(&request, remote_addr, &mut RequestData::new(), res_key)
+            .await;
+    }
+
+    assert!(check(Some("admin"), "user/delete").await.unwrap());
+    assert!(check(Some("alice"), "alice").await.unwrap());
+    assert!(!check(Some("alice"), "user/delete").await.unwrap());
+    let err = check(None, "alice").await.unwrap_err();
+    assert!(matches!(err.downcast::<ErrNo>(), Ok(ErrNo::LoginRequired)));
+}
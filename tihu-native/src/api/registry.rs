@@ -0,0 +1,190 @@
+use super::permission::ResKeyChecker;
+use super::ApiHandler;
+use super::PermissionProvider;
+use crate::http::Body;
+use crate::http::BoxBody;
+use crate::http::HttpHandler;
+use crate::http::RequestData;
+use crate::http::Router;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::{Request, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tihu::Api;
+use tihu::Handler;
+use tihu::SharedString;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ApiInfo {
+    pub namespace: SharedString,
+    pub res_key: Option<SharedString>,
+}
+
+/// A set of [`ApiHandler`]s sharing one [`PermissionProvider`], which also records every
+/// registered [`Api`] with its resource key, for example to build an admin UI.
+#[derive(Default)]
+pub struct ApiRegistry {
+    router: Router,
+    apis: Vec<ApiInfo>,
+    permission: Option<Arc<dyn ResKeyChecker>>,
+}
+
+impl ApiRegistry {
+    /// Create a registry without permission provider, Apis requiring a resource key are
+    /// then never allowed.
+    pub fn new() -> ApiRegistry {
+        Default::default()
+    }
+
+    pub fn with_permission<P>(provider: P) -> ApiRegistry
+    where
+        P: PermissionProvider,
+    {
+        ApiRegistry {
+            router: Router::new(),
+            apis: Vec::new(),
+            permission: Some(Arc::new(provider)),
+        }
+    }
+
+    pub fn register<A, H>(&mut self, handler: H) -> Result<(), ErrNo>
+    where
+        A: Api + 'static,
+        A::Input: DeserializeOwned + Send + 'static,
+        A::Output: Serialize + Send + 'static,
+        H: Handler<A::Input, Out = Result<A::Output, ErrNo>>,
+    {
+        let mut api_handler = ApiHandler::<A, H>::new(handler);
+        api_handler.permission = self.permission.clone();
+        self.router.register(api_handler)?;
+        self.apis.push(ApiInfo {
+            namespace: A::namespace(),
+            res_key: A::require_res_key(),
+        });
+        return Ok(());
+    }
+
+    /// All registered Apis, in registration order.
+    pub fn apis(&self) -> &[ApiInfo] {
+        &self.apis
+    }
+}
+
+#[async_trait]
+impl HttpHandler for ApiRegistry {
+    fn namespace(&self) -> &[SharedString] {
+        self.router.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        return self
+            .router
+            .handle(request, remote_addr, request_data, prefix)
+            .await;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_registry() {
+    use super::permission::UserPermission;
+    use crate::http::TestRequest;
+    use hyper::StatusCode;
+
+    struct Echo;
+
+    #[async_trait]
+    impl Api for Echo {
+        type Input = String;
+        type Output = String;
+        fn namespace() -> SharedString {
+            SharedString::from_static("test/echo")
+        }
+    }
+
+    struct Purge;
+
+    #[async_trait]
+    impl Api for Purge {
+        type Input = ();
+        type Output = bool;
+        fn namespace() -> SharedString {
+            SharedString::from_static("test/purge")
+        }
+        fn require_res_key() -> Option<SharedString> {
+            return Some(SharedString::from_static("purge"));
+        }
+    }
+
+    async fn purge(registry: &ApiRegistry, user: Option<&str>) -> (StatusCode, i32) {
+        let mut request = TestRequest::post("/test/purge").json(&());
+        if let Some(user) = user {
+            request = request.header("x-user", user);
+        }
+        let response = request.send(registry).await.unwrap();
+        return (
+            response.status,
+            response.api_response::<bool>().unwrap().code,
+        );
+    }
+
+    let mut registry = ApiRegistry::with_permission(UserPermission);
+    registry
+        .register::<Echo, _>(|input: String| async move { Ok(input) })
+        .unwrap();
+    registry
+        .register::<Purge, _>(|_: ()| async move { Ok(true) })
+        .unwrap();
+    assert_eq!(
+        vec![
+            ApiInfo {
+                namespace: SharedString::from_static("test/echo"),
+                res_key: None,
+            },
+            ApiInfo {
+                namespace: SharedString::from_static("test/purge"),
+                res_key: Some(SharedString::from_static("purge")),
+            },
+        ],
+        registry.apis()
+    );
+    assert!(registry
+        .register::<Echo, _>(|input: String| async move { Ok(input) })
+        .is_err());
+
+    let response = TestRequest::post("/test/echo")
+        .json("hi")
+        .send(&registry)
+        .await
+        .unwrap();
+    let response = response.api_response::<String>().unwrap();
+    assert_eq!(Some("hi".to_string()), response.data);
+    let response = TestRequest::post("/test/none")
+        .json("hi")
+        .send(&registry)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status);
+
+    assert_eq!((StatusCode::OK, 0), purge(&registry, Some("admin")).await);
+    assert_eq!((StatusCode::OK, 0), purge(&registry, Some("purge")).await);
+    // Api failures are responded with the status OK, the code tells them apart.
+    let not_allowed = (StatusCode::OK, ErrNo::NotAllowed.code());
+    assert_eq!(not_allowed, purge(&registry, Some("alice")).await);
+    let login_required = (StatusCode::OK, ErrNo::LoginRequired.code());
+    assert_eq!(login_required, purge(&registry, None).await);
+
+    let mut registry = ApiRegistry::new();
+    registry
+        .register::<Purge, _>(|_: ()| async move { Ok(true) })
+        .unwrap();
+    assert_eq!(not_allowed, purge(&registry, Some("admin")).await);
+}
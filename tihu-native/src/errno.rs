@@ -1,4 +1,5 @@
-use tihu::api::Response;
+use hyper::StatusCode;
+use tihu::api::{ErrCode, FieldError, Response};
 use tihu::SharedString;
 
 #[derive(thiserror::Error, Debug)]
@@ -61,50 +62,95 @@ pub enum ErrNo {
     NoCacheClient,
     #[error("缓存操作失败,{0}")]
     CacheOperationError(anyhow::Error),
+    #[error("请求数据太大，不能超过{0}字节")]
+    PayloadTooLarge(usize),
+    /// [`ErrNo::ParamInvalid`] with the invalid fields, responded in the `errors` of the
+    /// response with the same code.
+    #[error("参数无效,{}", join_field_errors(.0))]
+    ParamInvalidFields(Vec<FieldError>),
+}
+
+fn join_field_errors(errors: &[FieldError]) -> String {
+    return errors
+        .iter()
+        .map(|error| format!("{}:{}", error.path, error.message))
+        .collect::<Vec<_>>()
+        .join(";");
 }
 
 impl ErrNo {
     pub fn code(&self) -> i32 {
+        return self.err_code().code();
+    }
+    pub fn err_code(&self) -> ErrCode {
         return match *self {
-            ErrNo::LoginRequired => -1,
-            ErrNo::CommonError(_) => -2,
-            ErrNo::Other(_) => -3,
-            ErrNo::ConfigError(_) => -4,
-            ErrNo::Timeout(_) => -5,
-            ErrNo::NoService(_) => -6,
-            ErrNo::ServiceBusy(_) => -7,
-            ErrNo::ServicePaused => -8,
-            ErrNo::NoSuchApi => -9,
-            ErrNo::SerializeError(_) => -10,
-            ErrNo::DeserializeError(_) => -11,
-            ErrNo::ApiError(_) => -12,
-            ErrNo::Utf8Only => -13,
-            ErrNo::ParamFormatError => -14,
-            ErrNo::ParamInvalid(_) => -15,
-            ErrNo::TokenInvalid => -16,
-            ErrNo::NotAllowed => -17,
-            ErrNo::TooFrequent => -18,
-            ErrNo::MultipartRequired => -19,
-            ErrNo::UndefinedEnumValue(_) => -20,
-            ErrNo::NoDbClient => -21,
-            ErrNo::PrepareStatementError(_) => -22,
-            ErrNo::QueryError(_) => -23,
-            ErrNo::ExecuteError(_) => -24,
-            ErrNo::OpenTransactionError(_) => -25,
-            ErrNo::ExtractDataError(_) => -26,
-            ErrNo::CommitTransactionError(_) => -27,
-            ErrNo::NoCacheClient => -28,
-            ErrNo::CacheOperationError(_) => -29,
+            ErrNo::LoginRequired => ErrCode::LoginRequired,
+            ErrNo::CommonError(_) => ErrCode::CommonError,
+            ErrNo::Other(_) => ErrCode::Other,
+            ErrNo::ConfigError(_) => ErrCode::ConfigError,
+            ErrNo::Timeout(_) => ErrCode::Timeout,
+            ErrNo::NoService(_) => ErrCode::NoService,
+            ErrNo::ServiceBusy(_) => ErrCode::ServiceBusy,
+            ErrNo::ServicePaused => ErrCode::ServicePaused,
+            ErrNo::NoSuchApi => ErrCode::NoSuchApi,
+            ErrNo::SerializeError(_) => ErrCode::SerializeError,
+            ErrNo::DeserializeError(_) => ErrCode::DeserializeError,
+            ErrNo::ApiError(_) => ErrCode::ApiError,
+            ErrNo::Utf8Only => ErrCode::Utf8Only,
+            ErrNo::ParamFormatError => ErrCode::ParamFormatError,
+            ErrNo::ParamInvalid(_) => ErrCode::ParamInvalid,
+            ErrNo::TokenInvalid => ErrCode::TokenInvalid,
+            ErrNo::NotAllowed => ErrCode::NotAllowed,
+            ErrNo::TooFrequent => ErrCode::TooFrequent,
+            ErrNo::MultipartRequired => ErrCode::MultipartRequired,
+            ErrNo::UndefinedEnumValue(_) => ErrCode::UndefinedEnumValue,
+            ErrNo::NoDbClient => ErrCode::NoDbClient,
+            ErrNo::PrepareStatementError(_) => ErrCode::PrepareStatementError,
+            ErrNo::QueryError(_) => ErrCode::QueryError,
+            ErrNo::ExecuteError(_) => ErrCode::ExecuteError,
+            ErrNo::OpenTransactionError(_) => ErrCode::OpenTransactionError,
+            ErrNo::ExtractDataError(_) => ErrCode::ExtractDataError,
+            ErrNo::CommitTransactionError(_) => ErrCode::CommitTransactionError,
+            ErrNo::NoCacheClient => ErrCode::NoCacheClient,
+            ErrNo::CacheOperationError(_) => ErrCode::CacheOperationError,
+            ErrNo::PayloadTooLarge(_) => ErrCode::PayloadTooLarge,
+            ErrNo::ParamInvalidFields(_) => ErrCode::ParamInvalid,
         };
     }
     pub fn message(&self) -> SharedString {
         return self.to_string().into();
     }
+    /// The HTTP status code used when this error is returned as a whole response.
+    pub fn status_code(&self) -> StatusCode {
+        return match *self {
+            ErrNo::LoginRequired | ErrNo::TokenInvalid => StatusCode::UNAUTHORIZED,
+            ErrNo::NotAllowed => StatusCode::FORBIDDEN,
+            ErrNo::NoSuchApi => StatusCode::NOT_FOUND,
+            ErrNo::TooFrequent => StatusCode::TOO_MANY_REQUESTS,
+            ErrNo::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrNo::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ErrNo::NoService(_) | ErrNo::ServiceBusy(_) | ErrNo::ServicePaused => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrNo::DeserializeError(_)
+            | ErrNo::Utf8Only
+            | ErrNo::ParamFormatError
+            | ErrNo::ParamInvalid(_)
+            | ErrNo::ParamInvalidFields(_)
+            | ErrNo::MultipartRequired
+            | ErrNo::UndefinedEnumValue(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+    }
 }
 
 impl<T> From<ErrNo> for Response<T> {
     fn from(err_no: ErrNo) -> Response<T> {
-        return Response::failure(err_no.code(), err_no.message(), None);
+        let response = Response::failure(err_no.code(), err_no.message(), None);
+        match err_no {
+            ErrNo::ParamInvalidFields(errors) => return response.with_errors(errors),
+            _ => return response,
+        }
     }
 }
 
@@ -153,3 +199,26 @@ where
 {
     ErrNo::CommitTransactionError(error.into())
 }
+
+#[test]
+fn test_fields_invalid() {
+    let err_no = ErrNo::ParamInvalidFields(vec![
+        FieldError::new("name", "required", "不能为空"),
+        FieldError::new("emails[1]", "format", "邮箱格式不正确"),
+    ]);
+    assert_eq!(StatusCode::BAD_REQUEST, err_no.status_code());
+    assert_eq!(
+        "参数无效,name:不能为空;emails[1]:邮箱格式不正确",
+        err_no.to_string()
+    );
+    let response: Response<()> = err_no.into();
+    assert_eq!(ErrNo::ParamInvalid("".into()).code(), response.code);
+    assert_eq!(Some(ErrCode::ParamInvalid), response.err_code());
+    assert_eq!(
+        2,
+        response.errors.as_ref().map(Vec::len).unwrap_or_default()
+    );
+    let response: Response<()> = ErrNo::NotAllowed.into();
+    assert_eq!(Some(ErrCode::NotAllowed), response.err_code());
+    assert!(response.errors.is_none());
+}
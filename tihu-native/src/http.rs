@@ -1,3 +1,68 @@
+pub mod access_log;
+pub mod authorize;
+pub mod bearer;
+pub mod compression;
+pub mod cors;
+pub mod extract;
+pub mod limit;
+pub mod multipart;
+pub mod negotiate;
+pub mod proxy;
+pub mod rate_limit;
+pub mod router;
+pub mod server;
+pub mod session;
+pub mod sse;
+pub mod static_files;
+pub mod testing;
+pub mod websocket;
+
+pub use access_log::AccessLog;
+pub use access_log::RequestId;
+pub use authorize::Authorized;
+pub use bearer::BearerAuthorizer;
+pub use bearer::BearerToken;
+pub use bearer::Claims;
+pub use bearer::JwtVerifier;
+pub use bearer::TokenVerifier;
+pub use compression::Compression;
+pub use cors::Cors;
+pub use extract::BodyLimit;
+pub use extract::Form;
+pub use extract::Json;
+pub use extract::MatchedPrefix;
+pub use extract::PathParams;
+pub use extract::Query;
+pub use extract::RawBytes;
+pub use limit::ConcurrencyLimit;
+pub use limit::Pausable;
+pub use limit::PauseSwitch;
+pub use limit::Timeout;
+pub use multipart::Multipart;
+pub use multipart::MultipartLimit;
+pub use negotiate::Format;
+pub use negotiate::Negotiated;
+pub use proxy::LoadBalance;
+pub use proxy::ReverseProxy;
+pub use rate_limit::RateLimit;
+pub use rate_limit::RateLimiter;
+pub use router::Router;
+pub use server::Server;
+pub use session::MemoryStore;
+pub use session::Session;
+pub use session::SessionStore;
+pub use session::Sessions;
+pub use sse::Event;
+pub use sse::SseBody;
+pub use static_files::StaticFiles;
+pub use testing::TestRequest;
+pub use testing::TestResponse;
+pub use websocket::LayeredWebSocket;
+pub use websocket::WebSocket;
+pub use websocket::WebSocketHandler;
+pub use websocket::WebSocketUpgrade;
+
+use crate::ErrNo;
 use async_trait::async_trait;
 use bytes::Bytes;
 use bytes::BytesMut;
@@ -6,17 +71,22 @@ use futures::StreamExt;
 use futures::TryStreamExt;
 use headers::Cookie;
 use headers::HeaderMapExt;
+use hmac::{Hmac, Mac};
 use http::Extensions;
 use http_body_util::BodyExt;
 use hyper::body::Frame;
 use hyper::body::Incoming;
 use hyper::header::HeaderValue;
+use hyper::header::CONTENT_TYPE;
 use hyper::HeaderMap;
 use hyper::Method;
+use hyper::StatusCode;
 use hyper::Uri;
 use hyper::Version;
 use hyper::{Request, Response};
 use pin_project::pin_project;
+use serde::Serialize;
+use sha2::Sha256;
 use std::any::Any;
 use std::any::TypeId;
 use std::borrow::Cow;
@@ -32,21 +102,42 @@ use tihu::SharedString;
 pub type BoxBody = http_body_util::combinators::BoxBody<Bytes, anyhow::Error>;
 
 /// A body object for requests and responses.
-#[derive(Default)]
+///
+/// Bodies received by hyper are kept as is, other bodies are boxed.
 #[pin_project]
-pub struct Body(#[pin] pub(crate) BoxBody);
+pub struct Body(#[pin] BodyKind);
+
+#[pin_project(project = BodyKindProj)]
+enum BodyKind {
+    Incoming(#[pin] Incoming),
+    Boxed(#[pin] BoxBody),
+}
+
+impl Default for Body {
+    #[inline]
+    fn default() -> Self {
+        Body::empty()
+    }
+}
 
 impl From<Body> for BoxBody {
     #[inline]
     fn from(body: Body) -> Self {
-        body.0
+        body.into_inner()
     }
 }
 
 impl From<BoxBody> for Body {
     #[inline]
     fn from(body: BoxBody) -> Self {
-        Body(body)
+        Body(BodyKind::Boxed(body))
+    }
+}
+
+impl From<Incoming> for Body {
+    #[inline]
+    fn from(body: Incoming) -> Self {
+        Body(BodyKind::Incoming(body))
     }
 }
 
@@ -59,7 +150,7 @@ impl Debug for Body {
 impl From<&'static [u8]> for Body {
     #[inline]
     fn from(data: &'static [u8]) -> Self {
-        Self(BoxBody::new(
+        Body::from(BoxBody::new(
             http_body_util::Full::new(data.into()).map_err::<_, anyhow::Error>(|_| unreachable!()),
         ))
     }
@@ -68,7 +159,7 @@ impl From<&'static [u8]> for Body {
 impl From<&'static str> for Body {
     #[inline]
     fn from(data: &'static str) -> Self {
-        Self(BoxBody::new(
+        Body::from(BoxBody::new(
             http_body_util::Full::new(data.into()).map_err::<_, anyhow::Error>(|_| unreachable!()),
         ))
     }
@@ -77,7 +168,7 @@ impl From<&'static str> for Body {
 impl From<Bytes> for Body {
     #[inline]
     fn from(data: Bytes) -> Self {
-        Self(
+        Body::from(
             http_body_util::Full::new(data)
                 .map_err::<_, anyhow::Error>(|_| unreachable!())
                 .boxed(),
@@ -88,7 +179,7 @@ impl From<Bytes> for Body {
 impl From<Vec<u8>> for Body {
     #[inline]
     fn from(data: Vec<u8>) -> Self {
-        Self(
+        Body::from(
             http_body_util::Full::new(data.into())
                 .map_err::<_, anyhow::Error>(|_| unreachable!())
                 .boxed(),
@@ -99,7 +190,7 @@ impl From<Vec<u8>> for Body {
 impl From<Cow<'static, [u8]>> for Body {
     #[inline]
     fn from(data: Cow<'static, [u8]>) -> Self {
-        Self(
+        Body::from(
             http_body_util::Full::from(data)
                 .map_err::<_, anyhow::Error>(|_| unreachable!())
                 .boxed(),
@@ -151,7 +242,7 @@ impl Body {
         O: Into<Bytes> + 'static,
         E: Into<anyhow::Error> + 'static,
     {
-        Self(BoxBody::new(http_body_util::StreamBody::new(
+        Body::from(BoxBody::new(http_body_util::StreamBody::new(
             SyncStream::new(
                 stream
                     .map_ok(|data| Frame::data(data.into()))
@@ -169,16 +260,20 @@ impl Body {
     /// Create an empty body.
     #[inline]
     pub fn empty() -> Self {
-        Self(
+        Body::from(
             http_body_util::Empty::new()
                 .map_err::<_, anyhow::Error>(|_| unreachable!())
                 .boxed(),
         )
     }
 
+    /// The boxed body, bodies received by hyper are boxed on the first call.
     #[inline]
     pub fn into_inner(self) -> BoxBody {
-        self.0
+        match self.0 {
+            BodyKind::Incoming(body) => BoxBody::new(body.map_err(anyhow::Error::from)),
+            BodyKind::Boxed(body) => body,
+        }
     }
 }
 
@@ -189,8 +284,22 @@ impl hyper::body::Body for Body {
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
-        let this = self.project();
-        hyper::body::Body::poll_frame(this.0, cx)
+        match self.project().0.project() {
+            BodyKindProj::Incoming(body) => body.poll_frame(cx).map_err(anyhow::Error::from),
+            BodyKindProj::Boxed(body) => body.poll_frame(cx),
+        }
+    }
+    fn is_end_stream(&self) -> bool {
+        match &self.0 {
+            BodyKind::Incoming(body) => body.is_end_stream(),
+            BodyKind::Boxed(body) => body.is_end_stream(),
+        }
+    }
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        match &self.0 {
+            BodyKind::Incoming(body) => body.size_hint(),
+            BodyKind::Boxed(body) => body.size_hint(),
+        }
     }
 }
 
@@ -222,12 +331,62 @@ where
     return Ok(bytes.into());
 }
 
+/// Read the whole body like [`read_body`], but abort with [`ErrNo::PayloadTooLarge`] as soon
+/// as more than `limit` bytes are received.
+pub async fn read_body_limited<B>(mut body: B, limit: usize) -> Result<Bytes, anyhow::Error>
+where
+    B: hyper::body::Body<Data = Bytes> + Unpin,
+    B::Error: Into<anyhow::Error>,
+{
+    let mut bytes = BytesMut::new();
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(Into::into)?;
+        if let Some(frame) = frame.data_ref() {
+            if limit < bytes.len() + frame.len() {
+                return Err(ErrNo::PayloadTooLarge(limit).into());
+            }
+            bytes.extend_from_slice(frame);
+        }
+    }
+    return Ok(bytes.into());
+}
+
+/// Build a response with the given status whose body is `data` serialized as JSON.
+pub fn json_response<T>(status: StatusCode, data: &T) -> Result<Response<BoxBody>, anyhow::Error>
+where
+    T: Serialize + ?Sized,
+{
+    let body = serde_json::to_vec(data).map_err(ErrNo::SerializeError)?;
+    let response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(Body::from(body).into())?;
+    return Ok(response);
+}
+
+/// Build a JSON [`tihu::api::Response`] for `err_no`, using [`ErrNo::status_code`] as the status.
+pub fn err_no_response(err_no: ErrNo) -> Result<Response<BoxBody>, anyhow::Error> {
+    let status = err_no.status_code();
+    return json_response(status, &tihu::api::Response::<()>::from(err_no));
+}
+
+/// HMAC-SHA256 keyed with `key`, signing the session cookies and the JWTs.
+pub(crate) fn hmac_sha256(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC accepts keys of any length.
+    return Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+}
+
+/// Handles requests under its namespace.
+///
+/// Requests are taken with a [`Body`], so handlers also serve requests not received by
+/// hyper, like synthesized or batched ones. Requests from hyper are converted with
+/// `request.map(Body::from)`, which does not box the body.
 #[async_trait]
 pub trait HttpHandler: Sync + Send + 'static {
     fn namespace(&self) -> &[SharedString];
     async fn handle(
         &self,
-        request: Request<Incoming>,
+        request: Request<Body>,
         remote_addr: SocketAddr,
         request_data: &mut RequestData,
         prefix: Option<&str>,
@@ -238,17 +397,36 @@ pub trait HttpHandler: Sync + Send + 'static {
 pub trait HttpAuthorizer: Sync + Send + 'static {
     async fn authorize(
         &self,
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         remote_addr: SocketAddr,
         request_data: &mut RequestData,
         prefix: Option<&str>,
     ) -> Result<bool, anyhow::Error>;
+    /// The error responded when `authorize` returns `false`.
+    fn rejection(&self) -> ErrNo {
+        return ErrNo::NotAllowed;
+    }
 }
 
 #[async_trait]
 pub trait FromRequest: Sync + Send + 'static {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error>
+    where
+        Self: Sized;
+}
+
+/// Like [`FromRequest`], but for data extracted from the request body.
+///
+/// The body can only be read once, implementations should read it through the
+/// [`RawBytes`] cached in [`RequestData`] instead of polling it directly.
+#[async_trait]
+pub trait FromBody: Sync + Send + 'static {
+    async fn try_extract_body(
+        request: &mut Request<Body>,
         remote_addr: SocketAddr,
         request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error>
@@ -270,7 +448,7 @@ impl RequestData {
 impl RequestData {
     pub async fn try_get<T>(
         &mut self,
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         remote_addr: SocketAddr,
     ) -> Result<&T, anyhow::Error>
     where
@@ -291,9 +469,43 @@ impl RequestData {
             .ok_or_else(|| SharedString::from_static("Data not match the type!"))?;
         return Ok(data);
     }
+    pub async fn try_get_body<T>(
+        &mut self,
+        request: &mut Request<Body>,
+        remote_addr: SocketAddr,
+    ) -> Result<&T, anyhow::Error>
+    where
+        T: FromBody,
+    {
+        let type_id = TypeId::of::<T>();
+        if !self.data_map.contains_key(&type_id) {
+            let data = T::try_extract_body(request, remote_addr, self).await?;
+            self.data_map.insert(type_id, Box::new(data));
+        }
+        return self
+            .get::<T>()
+            .ok_or_else(|| SharedString::from_static("Data not match the type!").into());
+    }
+    /// Store data for later extractors or handlers, replacing data of the same type.
+    pub fn insert<T>(&mut self, data: T)
+    where
+        T: Sync + Send + 'static,
+    {
+        self.data_map.insert(TypeId::of::<T>(), Box::new(data));
+    }
+    /// Get data already extracted or inserted, without extracting it.
+    pub fn get<T>(&self) -> Option<&T>
+    where
+        T: Sync + Send + 'static,
+    {
+        return self
+            .data_map
+            .get(&TypeId::of::<T>())
+            .and_then(|data| data.downcast_ref::<T>());
+    }
     pub fn remove<T>(&mut self) -> Result<Option<Box<T>>, anyhow::Error>
     where
-        T: FromRequest,
+        T: Sync + Send + 'static,
     {
         let type_id = TypeId::of::<T>();
         if let Some(data) = self.data_map.remove(&type_id) {
@@ -312,7 +524,7 @@ impl RequestData {
     }
     pub async fn remove_or_get<T>(
         &mut self,
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         remote_addr: SocketAddr,
     ) -> Result<T, anyhow::Error>
     where
@@ -326,12 +538,28 @@ impl RequestData {
             return Ok(data);
         }
     }
+    pub async fn remove_or_get_body<T>(
+        &mut self,
+        request: &mut Request<Body>,
+        remote_addr: SocketAddr,
+    ) -> Result<T, anyhow::Error>
+    where
+        T: FromBody,
+    {
+        let data_opt = self.remove::<T>()?;
+        if let Some(data) = data_opt {
+            return Ok(*data);
+        } else {
+            let data = T::try_extract_body(request, remote_addr, self).await?;
+            return Ok(data);
+        }
+    }
 }
 
 #[async_trait]
 impl FromRequest for Option<Cookie> {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         _remote_addr: SocketAddr,
         _request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error> {
@@ -343,7 +571,7 @@ impl FromRequest for Option<Cookie> {
 #[async_trait]
 impl FromRequest for Method {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         _remote_addr: SocketAddr,
         _request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error> {
@@ -354,7 +582,7 @@ impl FromRequest for Method {
 #[async_trait]
 impl FromRequest for Uri {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         _remote_addr: SocketAddr,
         _request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error> {
@@ -365,7 +593,7 @@ impl FromRequest for Uri {
 #[async_trait]
 impl FromRequest for Version {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         _remote_addr: SocketAddr,
         _request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error> {
@@ -376,7 +604,7 @@ impl FromRequest for Version {
 #[async_trait]
 impl FromRequest for HeaderMap<HeaderValue> {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         _remote_addr: SocketAddr,
         _request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error> {
@@ -387,7 +615,7 @@ impl FromRequest for HeaderMap<HeaderValue> {
 #[async_trait]
 impl FromRequest for Extensions {
     async fn try_extract(
-        request: &Request<Incoming>,
+        request: &Request<Body>,
         _remote_addr: SocketAddr,
         _request_data: &mut RequestData,
     ) -> Result<Self, anyhow::Error> {
@@ -0,0 +1,529 @@
+use super::Body;
+use super::BoxBody;
+use super::FromRequest;
+use super::HttpHandler;
+use super::RequestData;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use hyper::body::{Frame, SizeHint};
+use hyper::header::{HeaderName, HeaderValue, REFERER, USER_AGENT};
+use hyper::{Method, Request, Response, StatusCode, Uri, Version};
+use pin_project::{pin_project, pinned_drop};
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use tihu::SharedString;
+
+/// Target of the access log records.
+pub const LOG_TARGET: &str = "access";
+/// Header carrying the request id by default.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Incoming request ids longer than this are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of the current request, taken from the request header when present, or generated.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RequestId(pub SharedString);
+
+impl RequestId {
+    /// A random id of 32 hex digits.
+    pub fn generate() -> RequestId {
+        let mut bytes = [0u8; 16];
+        if getrandom::getrandom(&mut bytes).is_err() {
+            // Fall back on the time, ids only need to be unique in practice.
+            let nanos = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            bytes = nanos.to_be_bytes();
+        }
+        let id = bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        return RequestId(id.into());
+    }
+
+    /// Accept an id given by the client or a proxy, when it is printable ASCII of sane length.
+    fn from_header(value: &HeaderValue) -> Option<RequestId> {
+        let value = value.to_str().ok()?;
+        let valid = !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value.bytes().all(|byte| byte.is_ascii_graphic());
+        if valid {
+            return Some(RequestId(value.to_string().into()));
+        } else {
+            return None;
+        }
+    }
+}
+
+/// The header of the request id set on [`AccessLog`], stored in [`RequestData`] so
+/// [`RequestId`] is read from it.
+#[derive(Clone, Debug)]
+struct RequestIdHeader(HeaderName);
+
+#[async_trait]
+impl FromRequest for RequestId {
+    async fn try_extract(
+        request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let header = match request_data.get::<RequestIdHeader>() {
+            Some(RequestIdHeader(header)) => header.as_str(),
+            None => REQUEST_ID_HEADER,
+        };
+        let request_id = request
+            .headers()
+            .get(header)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        return Ok(request_id);
+    }
+}
+
+/// A request served, logged once its response body is sent or dropped.
+#[derive(Clone, Debug)]
+pub struct AccessLogEntry {
+    pub request_id: SharedString,
+    pub remote_addr: SocketAddr,
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub time: SystemTime,
+    pub status: StatusCode,
+    /// Time from receiving the request to sending the last byte of the response.
+    pub latency: Duration,
+    /// Bytes of the response body sent.
+    pub bytes_sent: u64,
+}
+
+/// Formats the access log lines.
+pub trait AccessLogFormatter: Sync + Send + 'static {
+    fn format(&self, entry: &AccessLogEntry) -> String;
+}
+
+/// The Apache combined log format, followed by the request id and the latency in
+/// milliseconds.
+///
+/// Like nginx, `"`, `\` and the bytes not printable ASCII of the quoted fields are escaped as
+/// `\xHH`, so client headers cannot forge fields or lines.
+pub struct CombinedFormat;
+
+fn escape_quoted(value: &str) -> Cow<'_, str> {
+    let escaped = |byte: u8| b'"' == byte || b'\\' == byte || !(b' '..=b'~').contains(&byte);
+    if !value.bytes().any(escaped) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped_value = String::with_capacity(value.len() + 8);
+    for byte in value.bytes() {
+        if escaped(byte) {
+            escaped_value.push_str(&format!("\\x{:02X}", byte));
+        } else {
+            escaped_value.push(byte as char);
+        }
+    }
+    return Cow::Owned(escaped_value);
+}
+
+impl AccessLogFormatter for CombinedFormat {
+    fn format(&self, entry: &AccessLogEntry) -> String {
+        let time: DateTime<Local> = entry.time.into();
+        let bytes_sent = if 0 == entry.bytes_sent {
+            "-".to_string()
+        } else {
+            entry.bytes_sent.to_string()
+        };
+        return format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} \"{}\" \"{}\" {} {:.3}",
+            entry.remote_addr.ip(),
+            time.format("%d/%b/%Y:%H:%M:%S %z"),
+            entry.method,
+            escape_quoted(&entry.uri.to_string()),
+            entry.version,
+            entry.status.as_u16(),
+            bytes_sent,
+            escape_quoted(entry.referer.as_deref().unwrap_or("-")),
+            escape_quoted(entry.user_agent.as_deref().unwrap_or("-")),
+            entry.request_id,
+            entry.latency.as_secs_f64() * 1000.0,
+        );
+    }
+}
+
+/// One JSON object per line.
+pub struct JsonFormat;
+
+impl AccessLogFormatter for JsonFormat {
+    fn format(&self, entry: &AccessLogEntry) -> String {
+        let time: DateTime<Utc> = entry.time.into();
+        let line = serde_json::json!({
+            "time": time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "request_id": entry.request_id.as_ref(),
+            "remote_addr": entry.remote_addr.to_string(),
+            "method": entry.method.as_str(),
+            "uri": entry.uri.to_string(),
+            "version": format!("{:?}", entry.version),
+            "status": entry.status.as_u16(),
+            "latency_ms": entry.latency.as_secs_f64() * 1000.0,
+            "bytes_sent": entry.bytes_sent,
+            "user_agent": entry.user_agent,
+            "referer": entry.referer,
+        });
+        return line.to_string();
+    }
+}
+
+/// Assign each request a [`RequestId`], available from [`RequestData`] and echoed in the
+/// response headers, and log the requests to the wrapped handler through the `log` crate with
+/// the [`LOG_TARGET`] target.
+pub struct AccessLog<H> {
+    handler: H,
+    formatter: Arc<dyn AccessLogFormatter>,
+    header: HeaderName,
+}
+
+impl<H> AccessLog<H>
+where
+    H: HttpHandler,
+{
+    /// Log in the [`CombinedFormat`].
+    pub fn new(handler: H) -> AccessLog<H> {
+        AccessLog {
+            handler: handler,
+            formatter: Arc::new(CombinedFormat),
+            header: HeaderName::from_static(REQUEST_ID_HEADER),
+        }
+    }
+
+    pub fn formatter<F>(mut self, formatter: F) -> AccessLog<H>
+    where
+        F: AccessLogFormatter,
+    {
+        self.formatter = Arc::new(formatter);
+        return self;
+    }
+
+    /// Set the header the request id is read from and written to.
+    pub fn request_id_header(mut self, header: HeaderName) -> AccessLog<H> {
+        self.header = header;
+        return self;
+    }
+}
+
+fn log_entry(formatter: &dyn AccessLogFormatter, entry: &AccessLogEntry) {
+    log::info!(target: LOG_TARGET, "{}", formatter.format(entry));
+}
+
+#[async_trait]
+impl<H> HttpHandler for AccessLog<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let started = Instant::now();
+        let headers = request.headers();
+        let request_id = headers
+            .get(&self.header)
+            .and_then(RequestId::from_header)
+            .unwrap_or_else(RequestId::generate);
+        let header_str = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let mut entry = AccessLogEntry {
+            request_id: request_id.0.clone(),
+            remote_addr: remote_addr,
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            version: request.version(),
+            user_agent: header_str(USER_AGENT),
+            referer: header_str(REFERER),
+            time: SystemTime::now(),
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            latency: Duration::ZERO,
+            bytes_sent: 0,
+        };
+        request_data.insert(RequestIdHeader(self.header.clone()));
+        request_data.insert(request_id.clone());
+        let result = self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                entry.latency = started.elapsed();
+                log_entry(self.formatter.as_ref(), &entry);
+                return Err(err);
+            }
+        };
+        let (mut parts, body) = response.into_parts();
+        if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+            parts.headers.insert(self.header.clone(), value);
+        }
+        entry.status = parts.status;
+        let body = LoggedBody {
+            inner: body,
+            entry: Some(entry),
+            started: started,
+            formatter: self.formatter.clone(),
+        };
+        return Ok(Response::from_parts(parts, BoxBody::new(body)));
+    }
+}
+
+/// Counts the bytes sent, and logs the entry when the body ends or is dropped.
+#[pin_project(PinnedDrop)]
+struct LoggedBody {
+    #[pin]
+    inner: BoxBody,
+    entry: Option<AccessLogEntry>,
+    started: Instant,
+    formatter: Arc<dyn AccessLogFormatter>,
+}
+
+impl LoggedBody {
+    fn finish(
+        entry: &mut Option<AccessLogEntry>,
+        started: Instant,
+        formatter: &dyn AccessLogFormatter,
+    ) {
+        if let Some(mut entry) = entry.take() {
+            entry.latency = started.elapsed();
+            log_entry(formatter, &entry);
+        }
+    }
+}
+
+impl hyper::body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = anyhow::Error;
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(entry)) = (frame.data_ref(), this.entry.as_mut()) {
+                    entry.bytes_sent += data.len() as u64;
+                }
+            }
+            Poll::Ready(_) => {
+                LoggedBody::finish(this.entry, *this.started, this.formatter.as_ref());
+            }
+            Poll::Pending => {}
+        }
+        return poll;
+    }
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[pinned_drop]
+impl PinnedDrop for LoggedBody {
+    fn drop(self: Pin<&mut Self>) {
+        let this = self.project();
+        LoggedBody::finish(this.entry, *this.started, this.formatter.as_ref());
+    }
+}
+
+#[test]
+fn test_formats() {
+    let entry = AccessLogEntry {
+        request_id: SharedString::from_static("abc"),
+        remote_addr: "127.0.0.1:8080".parse().unwrap(),
+        method: Method::GET,
+        uri: "/index.html?a=1".parse().unwrap(),
+        version: Version::HTTP_11,
+        user_agent: Some("curl/8.0".to_string()),
+        referer: None,
+        time: SystemTime::UNIX_EPOCH,
+        status: StatusCode::OK,
+        latency: Duration::from_millis(12),
+        bytes_sent: 42,
+    };
+    let line = CombinedFormat.format(&entry);
+    assert!(line.starts_with("127.0.0.1 - - ["), "{}", line);
+    assert!(
+        line.ends_with("] \"GET /index.html?a=1 HTTP/1.1\" 200 42 \"-\" \"curl/8.0\" abc 12.000"),
+        "{}",
+        line
+    );
+    let forged = AccessLogEntry {
+        referer: Some("a\" 500 \\".to_string()),
+        user_agent: Some("curl\n127.0.0.1 - - [x] \"GET /\"".to_string()),
+        ..entry.clone()
+    };
+    let line = CombinedFormat.format(&forged);
+    assert!(
+        line.contains(" \"a\\x22 500 \\x5C\" \"curl\\x0A127.0.0.1 - - [x] \\x22GET /\\x22\" abc "),
+        "{}",
+        line
+    );
+    // Quotes in the path cannot end the request field either.
+    let forged = AccessLogEntry {
+        uri: "/a\"%20200%201%20\"x".parse().unwrap(),
+        ..entry.clone()
+    };
+    let line = CombinedFormat.format(&forged);
+    assert!(
+        line.contains("] \"GET /a\\x22%20200%201%20\\x22x HTTP/1.1\" 200 42 "),
+        "{}",
+        line
+    );
+    let line: serde_json::Value = serde_json::from_str(&JsonFormat.format(&entry)).unwrap();
+    assert_eq!("1970-01-01T00:00:00.000Z", line["time"]);
+    assert_eq!(200, line["status"]);
+    assert_eq!(42, line["bytes_sent"]);
+    assert_eq!(serde_json::Value::Null, line["referer"]);
+    assert!(RequestId::from_header(&HeaderValue::from_static("a b")).is_none());
+    assert_eq!(32, RequestId::generate().0.len());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_request_id_header() {
+    let request = Request::get("/")
+        .header(REQUEST_ID_HEADER, "a")
+        .header("x-trace-id", "b")
+        .body(Body::empty())
+        .unwrap();
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut request_data = RequestData::new();
+    let RequestId(id) = RequestId::try_extract(&request, remote_addr, &mut request_data)
+        .await
+        .unwrap();
+    assert_eq!("a", id.as_str());
+    request_data.insert(RequestIdHeader(HeaderName::from_static("x-trace-id")));
+    let RequestId(id) = RequestId::try_extract(&request, remote_addr, &mut request_data)
+        .await
+        .unwrap();
+    assert_eq!("b", id.as_str());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_access_log() {
+    use http_body_util::BodyExt;
+    use std::sync::Mutex;
+
+    /// Streams `ab`, `cd` and `ef`.
+    struct Chunks;
+
+    #[async_trait]
+    impl HttpHandler for Chunks {
+        fn namespace(&self) -> &[SharedString] {
+            &[]
+        }
+        async fn handle(
+            &self,
+            _request: Request<Body>,
+            _remote_addr: SocketAddr,
+            _request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let chunks = ["ab", "cd", "ef"].map(Ok::<_, std::io::Error>);
+            let body = Body::from_bytes_stream(futures::stream::iter(chunks));
+            return Ok(Response::new(body.into()));
+        }
+    }
+
+    /// Keeps the entries logged.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<AccessLogEntry>>>);
+
+    impl AccessLogFormatter for Capture {
+        fn format(&self, entry: &AccessLogEntry) -> String {
+            self.0.lock().unwrap().push(entry.clone());
+            return entry.request_id.to_string();
+        }
+    }
+
+    /// Enables the access log, so the formatter is called.
+    struct Enabled;
+
+    impl log::Log for Enabled {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            return LOG_TARGET == metadata.target();
+        }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                let _ = record.args().to_string();
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    let _ = log::set_logger(&Enabled);
+    log::set_max_level(log::LevelFilter::Info);
+    let capture = Capture::default();
+    let entries = || capture.0.lock().unwrap().clone();
+    let handler = AccessLog::new(Chunks).formatter(capture.clone());
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+
+    let request = Request::get("/chunks")
+        .header(REQUEST_ID_HEADER, "req-1")
+        .body(Body::empty())
+        .unwrap();
+    let response = handler
+        .handle(request, remote_addr, &mut RequestData::new(), None)
+        .await
+        .unwrap();
+    assert_eq!("req-1", response.headers()[REQUEST_ID_HEADER]);
+    let mut body = response.into_body();
+    for _ in 0..3 {
+        assert!(body.frame().await.unwrap().unwrap().is_data());
+        assert!(entries().is_empty());
+    }
+    assert!(body.frame().await.is_none());
+    let logged = entries();
+    assert_eq!(1, logged.len());
+    assert_eq!("req-1", logged[0].request_id.as_str());
+    assert_eq!(StatusCode::OK, logged[0].status);
+    assert_eq!(6, logged[0].bytes_sent);
+    drop(body);
+    assert_eq!(1, entries().len());
+
+    // A body dropped early, by a client going away, is logged with the bytes sent so far.
+    let request = Request::get("/chunks").body(Body::empty()).unwrap();
+    let response = handler
+        .handle(request, remote_addr, &mut RequestData::new(), None)
+        .await
+        .unwrap();
+    let request_id = response.headers()[REQUEST_ID_HEADER].clone();
+    assert_eq!(32, request_id.len());
+    let mut body = response.into_body();
+    body.frame().await.unwrap().unwrap();
+    assert_eq!(1, entries().len());
+    drop(body);
+    let logged = entries();
+    assert_eq!(2, logged.len());
+    assert_eq!(request_id, logged[1].request_id.as_str());
+    assert_eq!(2, logged[1].bytes_sent);
+}
@@ -0,0 +1,218 @@
+use super::err_no_response;
+use super::Body;
+use super::BoxBody;
+use super::HttpAuthorizer;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::{Request, Response};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tihu::SharedString;
+
+/// Run `authorizers` in order, returning the rejection response of the first one that denies
+/// the request.
+///
+/// An authorizer denies by returning `false`, which responds [`HttpAuthorizer::rejection`], or
+/// by returning an [`ErrNo`] error, which is responded as is. Other errors are propagated.
+pub async fn check_authorizers<'a, I>(
+    authorizers: I,
+    request: &Request<Body>,
+    remote_addr: SocketAddr,
+    request_data: &mut RequestData,
+    prefix: Option<&str>,
+) -> Result<Option<Response<BoxBody>>, anyhow::Error>
+where
+    I: IntoIterator<Item = &'a Arc<dyn HttpAuthorizer>>,
+{
+    for authorizer in authorizers {
+        let err_no = match authorizer
+            .authorize(request, remote_addr, request_data, prefix)
+            .await
+        {
+            Ok(true) => continue,
+            Ok(false) => authorizer.rejection(),
+            Err(err) => err.downcast::<ErrNo>()?,
+        };
+        return err_no_response(err_no).map(Some);
+    }
+    return Ok(None);
+}
+
+/// Wrap a handler so its authorizers run before it, with the same [`RequestData`], so values
+/// extracted by the authorizers are reused by the handler.
+pub struct Authorized<H> {
+    handler: H,
+    authorizers: Vec<Arc<dyn HttpAuthorizer>>,
+}
+
+impl<H> Authorized<H>
+where
+    H: HttpHandler,
+{
+    pub fn new(handler: H) -> Authorized<H> {
+        Authorized {
+            handler: handler,
+            authorizers: Vec::new(),
+        }
+    }
+
+    /// Append an authorizer, authorizers run in the order they are added.
+    pub fn authorizer<A>(mut self, authorizer: A) -> Authorized<H>
+    where
+        A: HttpAuthorizer,
+    {
+        self.authorizers.push(Arc::new(authorizer));
+        return self;
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for Authorized<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        if let Some(response) = check_authorizers(
+            &self.authorizers,
+            &request,
+            remote_addr,
+            request_data,
+            prefix,
+        )
+        .await?
+        {
+            return Ok(response);
+        }
+        return self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_authorized() {
+    use crate::http::TestRequest;
+    use hyper::StatusCode;
+
+    /// Accepts requests of the `admin` role, saving the role for the handler.
+    struct RoleAuthorizer;
+
+    #[async_trait]
+    impl HttpAuthorizer for RoleAuthorizer {
+        async fn authorize(
+            &self,
+            request: &Request<Body>,
+            _remote_addr: SocketAddr,
+            request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<bool, anyhow::Error> {
+            let role = match request.headers().get("x-role") {
+                Some(role) => role.to_str()?.to_string(),
+                None => return Ok(false),
+            };
+            request_data.insert(role.clone());
+            return Ok("admin" == role);
+        }
+        fn rejection(&self) -> ErrNo {
+            return ErrNo::LoginRequired;
+        }
+    }
+
+    /// Denies the requests of the `limited` path with an error.
+    struct LimitAuthorizer;
+
+    #[async_trait]
+    impl HttpAuthorizer for LimitAuthorizer {
+        async fn authorize(
+            &self,
+            request: &Request<Body>,
+            _remote_addr: SocketAddr,
+            _request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<bool, anyhow::Error> {
+            if request.uri().path().ends_with("/limited") {
+                return Err(ErrNo::TooFrequent.into());
+            }
+            return Ok(true);
+        }
+    }
+
+    struct Hello {
+        namespace: Vec<SharedString>,
+    }
+
+    #[async_trait]
+    impl HttpHandler for Hello {
+        fn namespace(&self) -> &[SharedString] {
+            &self.namespace
+        }
+        async fn handle(
+            &self,
+            _request: Request<Body>,
+            _remote_addr: SocketAddr,
+            request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let role = request_data.get::<String>().cloned().unwrap_or_default();
+            return Ok(Response::new(Body::from(format!("hello {}", role)).into()));
+        }
+    }
+
+    let handler = Authorized::new(Hello {
+        namespace: vec![SharedString::from_static("hello")],
+    })
+    .authorizer(LimitAuthorizer)
+    .authorizer(RoleAuthorizer);
+
+    let response = TestRequest::get("/hello")
+        .header("x-role", "admin")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("hello admin", response.text().unwrap());
+
+    let response = TestRequest::get("/hello")
+        .header("x-role", "guest")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status);
+    let response = response.api_response::<()>().unwrap();
+    assert_eq!(ErrNo::LoginRequired.code(), response.code);
+
+    // The first authorizer denying the request responds.
+    let response = TestRequest::get("/hello/limited")
+        .header("x-role", "admin")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status);
+    let response = response.api_response::<()>().unwrap();
+    assert_eq!(ErrNo::TooFrequent.code(), response.code);
+
+    let authorizers: Vec<Arc<dyn HttpAuthorizer>> = vec![Arc::new(RoleAuthorizer)];
+    let request = Request::get("/hello").body(Body::empty()).unwrap();
+    let mut request_data = RequestData::new();
+    let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let response = check_authorizers(&authorizers, &request, remote_addr, &mut request_data, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        Some(StatusCode::UNAUTHORIZED),
+        response.map(|response| response.status())
+    );
+}
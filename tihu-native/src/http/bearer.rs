@@ -0,0 +1,475 @@
+use super::hmac_sha256;
+use super::Body;
+use super::FromRequest;
+use super::HttpAuthorizer;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::Mac;
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, Request};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+use tihu::SharedString;
+
+/// Clock skew tolerated by [`JwtVerifier`] by default.
+pub const DEFAULT_LEEWAY: Duration = Duration::from_secs(60);
+
+/// The token of an `Authorization: Bearer` header.
+///
+/// Extracting it fails with [`ErrNo::LoginRequired`] when the header is missing, and with
+/// [`ErrNo::TokenInvalid`] when it is not a bearer token.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BearerToken(pub SharedString);
+
+impl BearerToken {
+    pub fn from_headers(headers: &HeaderMap) -> Result<BearerToken, ErrNo> {
+        let value = headers.get(AUTHORIZATION).ok_or(ErrNo::LoginRequired)?;
+        let value = value.to_str().map_err(|_| ErrNo::TokenInvalid)?;
+        let (scheme, token) = value.trim().split_once(' ').ok_or(ErrNo::TokenInvalid)?;
+        let token = token.trim();
+        if !scheme.eq_ignore_ascii_case("bearer") || token.is_empty() {
+            return Err(ErrNo::TokenInvalid);
+        }
+        return Ok(BearerToken(token.to_string().into()));
+    }
+}
+
+#[async_trait]
+impl FromRequest for BearerToken {
+    async fn try_extract(
+        request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        return Ok(BearerToken::from_headers(request.headers())?);
+    }
+}
+
+/// Verifies bearer tokens, returning their claims.
+#[async_trait]
+pub trait TokenVerifier: Sync + Send + 'static {
+    type Claims: Clone + Sync + Send + 'static;
+    /// Fail with [`ErrNo::TokenInvalid`] for tokens not valid, other errors are responded as
+    /// is.
+    async fn verify(&self, token: &str) -> Result<Self::Claims, ErrNo>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// The registered claims checked by [`JwtVerifier`], numeric dates are in seconds.
+#[derive(Deserialize)]
+struct RegisteredClaims {
+    exp: Option<f64>,
+    nbf: Option<f64>,
+    iat: Option<f64>,
+    aud: Option<Audience>,
+    iss: Option<String>,
+}
+
+/// Verify and sign HMAC-SHA256 (`HS256`) JSON Web Tokens, deserializing their payload into
+/// `C`.
+///
+/// `exp` is required by default, `nbf` and `iat` are checked when present, all with a
+/// leeway for clock skew. `aud` and `iss` are checked when configured.
+pub struct JwtVerifier<C> {
+    key: Vec<u8>,
+    leeway: Duration,
+    require_exp: bool,
+    audience: Option<SharedString>,
+    issuer: Option<SharedString>,
+    phantom: PhantomData<fn() -> C>,
+}
+
+impl<C> JwtVerifier<C>
+where
+    C: DeserializeOwned + Clone + Sync + Send + 'static,
+{
+    pub fn new(key: &[u8]) -> JwtVerifier<C> {
+        JwtVerifier {
+            key: key.to_vec(),
+            leeway: DEFAULT_LEEWAY,
+            require_exp: true,
+            audience: None,
+            issuer: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn leeway(mut self, leeway: Duration) -> JwtVerifier<C> {
+        self.leeway = leeway;
+        return self;
+    }
+
+    /// Accept tokens without `exp`, which never expire.
+    pub fn require_exp(mut self, require_exp: bool) -> JwtVerifier<C> {
+        self.require_exp = require_exp;
+        return self;
+    }
+
+    /// Require `aud` to be or to contain `audience`.
+    pub fn audience<A>(mut self, audience: A) -> JwtVerifier<C>
+    where
+        A: Into<SharedString>,
+    {
+        self.audience.replace(audience.into());
+        return self;
+    }
+
+    /// Require `iss` to be `issuer`.
+    pub fn issuer<I>(mut self, issuer: I) -> JwtVerifier<C>
+    where
+        I: Into<SharedString>,
+    {
+        self.issuer.replace(issuer.into());
+        return self;
+    }
+
+    /// Sign `claims` into a token this verifier accepts, given the registered claims are
+    /// set as required.
+    pub fn sign<T>(&self, claims: &T) -> Result<String, ErrNo>
+    where
+        T: Serialize + ?Sized,
+    {
+        let header = JwtHeader {
+            alg: "HS256".to_string(),
+            typ: Some("JWT".to_string()),
+        };
+        let header = serde_json::to_vec(&header).map_err(ErrNo::SerializeError)?;
+        let payload = serde_json::to_vec(claims).map_err(ErrNo::SerializeError)?;
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header),
+            URL_SAFE_NO_PAD.encode(payload)
+        );
+        let mut mac = hmac_sha256(&self.key);
+        mac.update(message.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        return Ok(format!("{}.{}", message, signature));
+    }
+
+    fn check_claims(&self, claims: &RegisteredClaims, now: f64) -> bool {
+        let leeway = self.leeway.as_secs_f64();
+        match claims.exp {
+            Some(exp) if exp + leeway <= now => return false,
+            None if self.require_exp => return false,
+            _ => {}
+        }
+        if claims.nbf.is_some_and(|nbf| now + leeway < nbf) {
+            return false;
+        }
+        if claims.iat.is_some_and(|iat| now + leeway < iat) {
+            return false;
+        }
+        if let Some(audience) = self.audience.as_ref() {
+            let matched = match claims.aud.as_ref() {
+                Some(Audience::One(aud)) => aud == audience.as_ref(),
+                Some(Audience::Many(auds)) => auds.iter().any(|aud| aud == audience.as_ref()),
+                None => false,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(issuer) = self.issuer.as_ref() {
+            if claims.iss.as_deref() != Some(issuer.as_ref()) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    fn verify_at(&self, token: &str, now: SystemTime) -> Result<C, ErrNo> {
+        let (message, signature) = token.rsplit_once('.').ok_or(ErrNo::TokenInvalid)?;
+        let (header, payload) = message.split_once('.').ok_or(ErrNo::TokenInvalid)?;
+        let decode = |part: &str| {
+            URL_SAFE_NO_PAD
+                .decode(part)
+                .map_err(|_| ErrNo::TokenInvalid)
+        };
+        let header: JwtHeader =
+            serde_json::from_slice(&decode(header)?).map_err(|_| ErrNo::TokenInvalid)?;
+        // Only the algorithm configured is accepted, never `none` or one chosen by the token.
+        if "HS256" != header.alg {
+            return Err(ErrNo::TokenInvalid);
+        }
+        let mut mac = hmac_sha256(&self.key);
+        mac.update(message.as_bytes());
+        mac.verify_slice(&decode(signature)?)
+            .map_err(|_| ErrNo::TokenInvalid)?;
+        let payload = decode(payload)?;
+        let registered: RegisteredClaims =
+            serde_json::from_slice(&payload).map_err(|_| ErrNo::TokenInvalid)?;
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        if !self.check_claims(&registered, now) {
+            return Err(ErrNo::TokenInvalid);
+        }
+        return serde_json::from_slice(&payload).map_err(|_| ErrNo::TokenInvalid);
+    }
+}
+
+#[async_trait]
+impl<C> TokenVerifier for JwtVerifier<C>
+where
+    C: DeserializeOwned + Clone + Sync + Send + 'static,
+{
+    type Claims = C;
+    async fn verify(&self, token: &str) -> Result<C, ErrNo> {
+        return self.verify_at(token, SystemTime::now());
+    }
+}
+
+/// The claims of the bearer token verified by [`BearerAuthorizer`].
+#[derive(Clone, Debug)]
+pub struct Claims<C>(pub C);
+
+#[async_trait]
+impl<C> FromRequest for Claims<C>
+where
+    C: Clone + Sync + Send + 'static,
+{
+    async fn try_extract(
+        _request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let claims = request_data
+            .get::<Claims<C>>()
+            .cloned()
+            .ok_or_else(|| ErrNo::ConfigError(SharedString::from_static("请求没有经过令牌认证")))?;
+        return Ok(claims);
+    }
+}
+
+/// Authorize requests carrying a bearer token accepted by the verifier, making its
+/// [`Claims`] available from [`RequestData`].
+///
+/// Requests without a token are rejected with [`ErrNo::LoginRequired`], and requests with a
+/// token not valid with [`ErrNo::TokenInvalid`].
+pub struct BearerAuthorizer<V> {
+    verifier: V,
+}
+
+impl<V> BearerAuthorizer<V>
+where
+    V: TokenVerifier,
+{
+    pub fn new(verifier: V) -> BearerAuthorizer<V> {
+        BearerAuthorizer { verifier: verifier }
+    }
+}
+
+#[async_trait]
+impl<V> HttpAuthorizer for BearerAuthorizer<V>
+where
+    V: TokenVerifier,
+{
+    async fn authorize(
+        &self,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        _prefix: Option<&str>,
+    ) -> Result<bool, anyhow::Error> {
+        let BearerToken(token) = request_data
+            .try_get::<BearerToken>(request, remote_addr)
+            .await?
+            .clone();
+        let claims = self.verifier.verify(&token).await?;
+        request_data.insert(Claims(claims));
+        return Ok(true);
+    }
+    fn rejection(&self) -> ErrNo {
+        return ErrNo::TokenInvalid;
+    }
+}
+
+#[test]
+fn test_jwt() {
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+    struct UserClaims {
+        sub: String,
+        exp: u64,
+        #[serde(default)]
+        aud: Vec<String>,
+        #[serde(default)]
+        nbf: Option<u64>,
+    }
+
+    let mut headers = HeaderMap::new();
+    assert!(matches!(
+        BearerToken::from_headers(&headers),
+        Err(ErrNo::LoginRequired)
+    ));
+    headers.insert(AUTHORIZATION, "Basic YTpi".parse().unwrap());
+    assert!(matches!(
+        BearerToken::from_headers(&headers),
+        Err(ErrNo::TokenInvalid)
+    ));
+    headers.insert(AUTHORIZATION, "bearer abc.def.ghi".parse().unwrap());
+    assert_eq!(
+        "abc.def.ghi",
+        BearerToken::from_headers(&headers).unwrap().0.as_ref()
+    );
+
+    let verifier = JwtVerifier::<UserClaims>::new(b"secret")
+        .audience("api")
+        .leeway(Duration::from_secs(5));
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let claims = UserClaims {
+        sub: "u1".to_string(),
+        exp: 1_000_010,
+        aud: vec!["web".to_string(), "api".to_string()],
+        nbf: None,
+    };
+    let token = verifier.sign(&claims).unwrap();
+    assert_eq!(claims, verifier.verify_at(&token, now).unwrap());
+    // Expired, with and without the leeway.
+    assert!(verifier
+        .verify_at(&token, now + Duration::from_secs(14))
+        .is_ok());
+    assert!(verifier
+        .verify_at(&token, now + Duration::from_secs(15))
+        .is_err());
+    let early = verifier
+        .sign(&UserClaims {
+            nbf: Some(1_000_009),
+            ..claims.clone()
+        })
+        .unwrap();
+    assert!(verifier.verify_at(&early, now).is_err());
+    let other_audience = verifier
+        .sign(&UserClaims {
+            aud: vec!["web".to_string()],
+            ..claims.clone()
+        })
+        .unwrap();
+    assert!(verifier.verify_at(&other_audience, now).is_err());
+    let forged = JwtVerifier::<UserClaims>::new(b"other")
+        .sign(&claims)
+        .unwrap();
+    assert!(matches!(
+        verifier.verify_at(&forged, now),
+        Err(ErrNo::TokenInvalid)
+    ));
+    let unsigned = format!(
+        "{}.{}.",
+        URL_SAFE_NO_PAD.encode(br#"{"alg":"none"}"#),
+        token.split('.').nth(1).unwrap()
+    );
+    assert!(verifier.verify_at(&unsigned, now).is_err());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_bearer_authorizer() {
+    use super::{Authorized, BoxBody, HttpHandler, TestRequest};
+    use hyper::{Response, StatusCode};
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    struct UserClaims {
+        sub: String,
+        exp: u64,
+    }
+
+    /// Greets the subject of the token.
+    struct Hello;
+
+    #[async_trait]
+    impl HttpHandler for Hello {
+        fn namespace(&self) -> &[SharedString] {
+            &[]
+        }
+        async fn handle(
+            &self,
+            request: Request<Body>,
+            remote_addr: SocketAddr,
+            request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let Claims(claims) = request_data
+                .try_get::<Claims<UserClaims>>(&request, remote_addr)
+                .await?;
+            return Ok(Response::new(
+                Body::from(format!("hello {}", claims.sub)).into(),
+            ));
+        }
+    }
+
+    let exp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+    let claims = UserClaims {
+        sub: "u1".to_string(),
+        exp: exp,
+    };
+    let token = JwtVerifier::<UserClaims>::new(b"secret")
+        .sign(&claims)
+        .unwrap();
+    let forged = JwtVerifier::<UserClaims>::new(b"other")
+        .sign(&claims)
+        .unwrap();
+    let handler = Authorized::new(Hello).authorizer(BearerAuthorizer::new(
+        JwtVerifier::<UserClaims>::new(b"secret"),
+    ));
+
+    let response = TestRequest::get("/")
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("hello u1", response.text().unwrap());
+
+    let response = TestRequest::get("/").send(&handler).await.unwrap();
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status);
+    let response = response.api_response::<()>().unwrap();
+    assert_eq!(ErrNo::LoginRequired.code(), response.code);
+
+    for authorization in [
+        format!("Bearer {}", forged),
+        "Bearer abc.def.ghi".to_string(),
+        format!("Basic {}", token),
+    ] {
+        let response = TestRequest::get("/")
+            .header(AUTHORIZATION, authorization.as_str())
+            .send(&handler)
+            .await
+            .unwrap();
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            response.status,
+            "{}",
+            authorization
+        );
+        let response = response.api_response::<()>().unwrap();
+        assert_eq!(
+            ErrNo::TokenInvalid.code(),
+            response.code,
+            "{}",
+            authorization
+        );
+    }
+}
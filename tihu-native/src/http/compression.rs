@@ -0,0 +1,479 @@
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder};
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+use async_compression::Level;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use http_body_util::BodyExt;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+    ETAG, VARY,
+};
+use hyper::{HeaderMap, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tihu::SharedString;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// Bodies smaller than this are not compressed by default, compression would not pay off.
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Parse a `Content-Encoding` value, `Ok(None)` means the identity encoding.
+    pub fn parse(value: &str) -> Result<Option<Encoding>, ErrNo> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("br") {
+            return Ok(Some(Encoding::Brotli));
+        } else if value.eq_ignore_ascii_case("gzip") || value.eq_ignore_ascii_case("x-gzip") {
+            return Ok(Some(Encoding::Gzip));
+        } else if value.eq_ignore_ascii_case("deflate") {
+            return Ok(Some(Encoding::Deflate));
+        } else if value.is_empty() || value.eq_ignore_ascii_case("identity") {
+            return Ok(None);
+        } else {
+            return Err(ErrNo::ParamFormatError);
+        }
+    }
+
+    /// Choose the preferred encoding of an `Accept-Encoding` value, by quality and then in the
+    /// order brotli, gzip, deflate.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut wildcard = None;
+        let mut qualities = [None; 3];
+        for item in accept_encoding.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .filter_map(|quality| quality.trim().parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            if "*" == coding {
+                wildcard.replace(quality);
+            } else if let Ok(Some(encoding)) = Encoding::parse(coding) {
+                qualities[encoding as usize].replace(quality);
+            }
+        }
+        let mut preferred = None;
+        for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+            let quality = qualities[encoding as usize].or(wildcard).unwrap_or(0.0);
+            if 0.0 < quality && preferred.is_none_or(|(_, max)| max < quality) {
+                preferred.replace((encoding, quality));
+            }
+        }
+        return preferred.map(|(encoding, _)| encoding);
+    }
+}
+
+/// An error of the body itself, told apart from the errors of the decoders reading it.
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+struct BodyError(anyhow::Error);
+
+fn into_reader<B>(body: B) -> impl AsyncBufRead + Send
+where
+    B: hyper::body::Body<Data = Bytes> + Send,
+    B::Error: Into<anyhow::Error>,
+{
+    let stream = body
+        .into_data_stream()
+        .map_err(|err| std::io::Error::other(BodyError(err.into())));
+    return StreamReader::new(stream);
+}
+
+fn encoder<'a, R>(reader: R, encoding: Encoding) -> Pin<Box<dyn AsyncRead + Send + 'a>>
+where
+    R: AsyncBufRead + Send + 'a,
+{
+    match encoding {
+        // The default brotli quality is too slow for on the fly compression.
+        Encoding::Brotli => Box::pin(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+        Encoding::Gzip => Box::pin(GzipEncoder::new(reader)),
+        Encoding::Deflate => Box::pin(ZlibEncoder::new(reader)),
+    }
+}
+
+fn decoder<'a, R>(reader: R, encoding: Encoding) -> Pin<Box<dyn AsyncRead + Send + 'a>>
+where
+    R: AsyncBufRead + Send + 'a,
+{
+    match encoding {
+        Encoding::Brotli => Box::pin(BrotliDecoder::new(reader)),
+        Encoding::Gzip => Box::pin(GzipDecoder::new(reader)),
+        Encoding::Deflate => Box::pin(ZlibDecoder::new(reader)),
+    }
+}
+
+/// Compress `body` while it is streamed.
+pub fn compress_body(body: Body, encoding: Encoding) -> Body {
+    return Body::from_bytes_stream(ReaderStream::new(encoder(into_reader(body), encoding)));
+}
+
+/// Decompress `body` while it is streamed.
+pub fn decompress_body(body: Body, encoding: Encoding) -> Body {
+    return Body::from_bytes_stream(ReaderStream::new(decoder(into_reader(body), encoding)));
+}
+
+/// Read and decompress the whole body, aborting with [`ErrNo::PayloadTooLarge`] once the
+/// decompressed data exceeds `limit`.
+///
+/// Data the decoder cannot decompress is reported as [`ErrNo::ParamFormatError`], errors
+/// reading `body` are returned as is.
+pub async fn read_body_decompressed<B>(
+    body: B,
+    encoding: Encoding,
+    limit: usize,
+) -> Result<Bytes, anyhow::Error>
+where
+    B: hyper::body::Body<Data = Bytes> + Send,
+    B::Error: Into<anyhow::Error>,
+{
+    let mut bytes = Vec::new();
+    let result = decoder(into_reader(body), encoding)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut bytes)
+        .await;
+    if let Err(err) = result {
+        match err.into_inner().map(|err| err.downcast::<BodyError>()) {
+            Some(Ok(body_error)) => return Err(body_error.0),
+            _ => return Err(ErrNo::ParamFormatError.into()),
+        }
+    }
+    if limit < bytes.len() {
+        return Err(ErrNo::PayloadTooLarge(limit).into());
+    }
+    return Ok(bytes.into());
+}
+
+/// Whether content of this type is compressed already, or must not be buffered.
+fn is_incompressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if "image/svg+xml" == essence {
+        return false;
+    }
+    return essence.starts_with("image/")
+        || essence.starts_with("audio/")
+        || essence.starts_with("video/")
+        || essence.starts_with("font/woff")
+        || [
+            "application/gzip",
+            "application/x-gzip",
+            "application/zip",
+            "application/x-7z-compressed",
+            "application/x-rar-compressed",
+            "application/x-bzip2",
+            "application/x-xz",
+            "application/zstd",
+            "application/octet-stream",
+            "application/pdf",
+            "text/event-stream",
+        ]
+        .contains(&essence.as_str());
+}
+
+/// Compress the responses of the wrapped handler according to the `Accept-Encoding` of the
+/// request.
+///
+/// Responses with an encoding already, partial content, incompressible content types, or a
+/// known size below the minimum size are left untouched.
+pub struct Compression<H> {
+    handler: H,
+    min_size: usize,
+}
+
+impl<H> Compression<H>
+where
+    H: HttpHandler,
+{
+    pub fn new(handler: H) -> Compression<H> {
+        Compression {
+            handler: handler,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    pub fn min_size(mut self, min_size: usize) -> Compression<H> {
+        self.min_size = min_size;
+        return self;
+    }
+
+    fn should_compress(&self, status: StatusCode, headers: &HeaderMap, body: &BoxBody) -> bool {
+        if status.is_informational()
+            || StatusCode::NO_CONTENT == status
+            || StatusCode::NOT_MODIFIED == status
+            || headers.contains_key(CONTENT_ENCODING)
+            || headers.contains_key(CONTENT_RANGE)
+        {
+            return false;
+        }
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        if content_type.is_some_and(is_incompressible) {
+            return false;
+        }
+        let size = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .or_else(|| hyper::body::Body::size_hint(body).exact());
+        return size.is_none_or(|size| self.min_size as u64 <= size);
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for Compression<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let encoding = request
+            .headers()
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(",");
+        let encoding = Encoding::negotiate(&encoding);
+        let response = self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await?;
+        let (mut parts, body) = response.into_parts();
+        if !self.should_compress(parts.status, &parts.headers, &body) {
+            return Ok(Response::from_parts(parts, body));
+        }
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return Ok(Response::from_parts(parts, body)),
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        // The compressed representation is not byte for byte the same as the original one.
+        if let Some(etag) = parts.headers.get(ETAG) {
+            if !etag.as_bytes().starts_with(b"W/") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                let weak = HeaderValue::from_bytes(&weak)?;
+                parts.headers.insert(ETAG, weak);
+            }
+        }
+        let body = compress_body(body.into(), encoding);
+        return Ok(Response::from_parts(parts, body.into()));
+    }
+}
+
+#[test]
+fn test_negotiate() {
+    let cases = [
+        ("", None),
+        ("identity", None),
+        ("gzip", Some(Encoding::Gzip)),
+        ("deflate, gzip", Some(Encoding::Gzip)),
+        ("gzip, deflate, br", Some(Encoding::Brotli)),
+        ("br;q=0.5, gzip;q=0.8", Some(Encoding::Gzip)),
+        ("br;q=0, *", Some(Encoding::Gzip)),
+        ("*;q=0", None),
+        ("GZIP;q=1.0, unknown", Some(Encoding::Gzip)),
+    ];
+    for (accept_encoding, expected) in cases {
+        assert_eq!(
+            expected,
+            Encoding::negotiate(accept_encoding),
+            "{}",
+            accept_encoding
+        );
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_compress_round_trip() {
+    let data = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ".repeat(100);
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let compressed = super::read_body(compress_body(Body::from(data.clone()), encoding))
+            .await
+            .unwrap();
+        assert!(compressed.len() < data.len());
+        let decompressed =
+            read_body_decompressed(Body::from(compressed.clone()), encoding, 1 << 20)
+                .await
+                .unwrap();
+        assert_eq!(data.as_bytes(), &decompressed[..]);
+        read_body_decompressed(Body::from(compressed), encoding, 100)
+            .await
+            .expect_err("expected payload too large");
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_read_body_errors() {
+    let err = read_body_decompressed(Body::from("not gzip"), Encoding::Gzip, 1 << 20)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast::<ErrNo>(),
+        Ok(ErrNo::ParamFormatError)
+    ));
+
+    // Errors of the body are not taken for corrupted data.
+    let compressed = super::read_body(compress_body(Body::from("hello"), Encoding::Gzip))
+        .await
+        .unwrap();
+    let body = Body::from_bytes_stream(futures::stream::iter([
+        Ok(compressed.slice(..4)),
+        Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset)),
+    ]));
+    let err = read_body_decompressed(body, Encoding::Gzip, 1 << 20)
+        .await
+        .unwrap_err();
+    let err = err.downcast::<std::io::Error>().unwrap();
+    assert_eq!(std::io::ErrorKind::ConnectionReset, err.kind());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_compression() {
+    use super::TestRequest;
+
+    /// Responds the page named by the path, with the headers of that kind of page.
+    struct Pages;
+
+    #[async_trait]
+    impl HttpHandler for Pages {
+        fn namespace(&self) -> &[SharedString] {
+            &[]
+        }
+        async fn handle(
+            &self,
+            request: Request<Body>,
+            _remote_addr: SocketAddr,
+            _request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let page = request.uri().path().trim_start_matches('/');
+            let (content_type, size) = match page {
+                "small" => ("text/plain", 100),
+                "image" => ("image/png", 2000),
+                _ => ("text/plain", 2000),
+            };
+            let mut response = Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .header(CONTENT_LENGTH, size);
+            if "encoded" == page {
+                response = response.header(CONTENT_ENCODING, "gzip");
+            }
+            if "weak" == page {
+                response = response.header(ETAG, "W/\"abc\"");
+            } else {
+                response = response.header(ETAG, "\"abc\"");
+            }
+            return Ok(response.body(Body::from("a".repeat(size)).into())?);
+        }
+    }
+
+    let handler = Compression::new(Pages);
+    let response = TestRequest::get("/text")
+        .header(ACCEPT_ENCODING, "gzip, deflate")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!("gzip", response.headers[CONTENT_ENCODING]);
+    assert_eq!("accept-encoding", response.headers[VARY]);
+    assert_eq!("W/\"abc\"", response.headers[ETAG]);
+    assert!(!response.headers.contains_key(CONTENT_LENGTH));
+    let body = read_body_decompressed(Body::from(response.body), Encoding::Gzip, 1 << 20)
+        .await
+        .unwrap();
+    assert_eq!("a".repeat(2000).as_bytes(), &body[..]);
+
+    // Uncompressed responses still vary by the encodings accepted.
+    let response = TestRequest::get("/text").send(&handler).await.unwrap();
+    assert!(!response.headers.contains_key(CONTENT_ENCODING));
+    assert_eq!("accept-encoding", response.headers[VARY]);
+    assert_eq!("\"abc\"", response.headers[ETAG]);
+    assert_eq!(2000, response.body.len());
+
+    let response = TestRequest::get("/weak")
+        .header(ACCEPT_ENCODING, "br")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!("br", response.headers[CONTENT_ENCODING]);
+    assert_eq!("W/\"abc\"", response.headers[ETAG]);
+
+    for (page, content_encoding) in [("small", None), ("image", None), ("encoded", Some("gzip"))] {
+        let response = TestRequest::get(&format!("/{}", page))
+            .header(ACCEPT_ENCODING, "br, gzip")
+            .send(&handler)
+            .await
+            .unwrap();
+        assert_eq!(
+            content_encoding,
+            response
+                .headers
+                .get(CONTENT_ENCODING)
+                .map(|value| value.to_str().unwrap()),
+            "page: {}",
+            page
+        );
+        assert!(!response.headers.contains_key(VARY), "page: {}", page);
+        assert_eq!("\"abc\"", response.headers[ETAG], "page: {}", page);
+        assert_eq!(
+            response.headers[CONTENT_LENGTH],
+            response.body.len().to_string(),
+            "page: {}",
+            page
+        );
+    }
+
+    let handler = Compression::new(Pages).min_size(100);
+    let response = TestRequest::get("/small")
+        .header(ACCEPT_ENCODING, "deflate")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!("deflate", response.headers[CONTENT_ENCODING]);
+}
@@ -0,0 +1,468 @@
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::header::{
+    HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tihu::SharedString;
+
+enum AllowOrigin {
+    Any,
+    List(Vec<HeaderValue>),
+    Predicate(Arc<dyn Fn(&str) -> bool + Sync + Send>),
+}
+
+fn join<I, T>(values: I) -> Option<HeaderValue>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<str>,
+{
+    let values = values
+        .into_iter()
+        .map(|value| value.as_ref().to_string())
+        .collect::<Vec<_>>();
+    if values.is_empty() {
+        return None;
+    }
+    return HeaderValue::from_str(&values.join(", ")).ok();
+}
+
+fn any_origin_with_credentials() -> ErrNo {
+    return ErrNo::ConfigError(SharedString::from_static(
+        "允许任意跨域来源时不能允许携带凭据",
+    ));
+}
+
+/// Answer CORS preflight requests to the wrapped handler, and add the CORS headers to its
+/// responses to allowed origins.
+///
+/// No origin is allowed by default. Requests from origins not allowed are passed to the
+/// handler unchanged, and their preflight requests are answered without CORS headers, so
+/// browsers block them. So are preflight requests for methods or headers not allowed.
+pub struct Cors<H> {
+    handler: H,
+    origins: AllowOrigin,
+    methods: Vec<Method>,
+    /// `None` allows the headers requested by the preflight request.
+    headers: Option<Vec<HeaderName>>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl<H> Cors<H>
+where
+    H: HttpHandler,
+{
+    pub fn new(handler: H) -> Cors<H> {
+        Cors {
+            handler: handler,
+            origins: AllowOrigin::List(Vec::new()),
+            methods: vec![Method::GET, Method::HEAD, Method::POST],
+            headers: None,
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allow an origin, like `https://example.com`, failing with [`ErrNo::ConfigError`] when
+    /// it is not a valid header value.
+    pub fn allow_origin(mut self, origin: &str) -> Result<Cors<H>, ErrNo> {
+        let origin = HeaderValue::from_str(origin)
+            .map_err(|_| ErrNo::ConfigError(format!("跨域来源\"{}\"不正确", origin).into()))?;
+        match &mut self.origins {
+            AllowOrigin::List(origins) => origins.push(origin),
+            _ => self.origins = AllowOrigin::List(vec![origin]),
+        }
+        return Ok(self);
+    }
+
+    /// Allow every origin, with the `*` wildcard.
+    ///
+    /// Fails with [`ErrNo::ConfigError`] when credentials are allowed, since any site could
+    /// then make credentialed requests and read the responses. List the origins or use
+    /// [`Cors::allow_origin_fn`] instead.
+    pub fn allow_any_origin(mut self) -> Result<Cors<H>, ErrNo> {
+        if self.credentials {
+            return Err(any_origin_with_credentials());
+        }
+        self.origins = AllowOrigin::Any;
+        return Ok(self);
+    }
+
+    /// Allow the origins `predicate` returns `true` for, instead of a list.
+    pub fn allow_origin_fn<F>(mut self, predicate: F) -> Cors<H>
+    where
+        F: Fn(&str) -> bool + Sync + Send + 'static,
+    {
+        self.origins = AllowOrigin::Predicate(Arc::new(predicate));
+        return self;
+    }
+
+    /// Set the methods allowed for preflight requests, `GET`, `HEAD` and `POST` by default.
+    pub fn allow_methods<I>(mut self, methods: I) -> Cors<H>
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        self.methods = methods.into_iter().collect();
+        return self;
+    }
+
+    /// Set the request headers allowed for preflight requests, any header requested is allowed
+    /// by default.
+    pub fn allow_headers<I>(mut self, headers: I) -> Cors<H>
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.headers = Some(headers.into_iter().collect());
+        return self;
+    }
+
+    /// Set the response headers readable by scripts besides the CORS-safelisted ones.
+    pub fn expose_headers<I>(mut self, headers: I) -> Cors<H>
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.expose_headers = headers.into_iter().collect();
+        return self;
+    }
+
+    /// Allow requests with cookies or HTTP authentication.
+    ///
+    /// Fails with [`ErrNo::ConfigError`] when any origin is allowed, credentials require the
+    /// origins to be listed or matched by [`Cors::allow_origin_fn`].
+    pub fn allow_credentials(mut self, credentials: bool) -> Result<Cors<H>, ErrNo> {
+        if credentials && matches!(self.origins, AllowOrigin::Any) {
+            return Err(any_origin_with_credentials());
+        }
+        self.credentials = credentials;
+        return Ok(self);
+    }
+
+    /// Set how long browsers may cache preflight responses.
+    pub fn max_age(mut self, max_age: Duration) -> Cors<H> {
+        self.max_age = Some(max_age);
+        return self;
+    }
+
+    fn is_allowed(&self, origin: &HeaderValue) -> bool {
+        match &self.origins {
+            AllowOrigin::Any => return true,
+            AllowOrigin::List(origins) => return origins.contains(origin),
+            AllowOrigin::Predicate(predicate) => {
+                return origin.to_str().is_ok_and(|origin| predicate(origin));
+            }
+        }
+    }
+
+    /// Add the headers common to preflight and actual responses.
+    fn write_origin_headers(&self, headers: &mut HeaderMap, origin: Option<&HeaderValue>) {
+        let origin = match origin {
+            Some(origin) if self.is_allowed(origin) => origin,
+            _ => {
+                if !matches!(self.origins, AllowOrigin::Any) {
+                    headers.append(VARY, HeaderValue::from_static("origin"));
+                }
+                return;
+            }
+        };
+        // Credentials are never allowed together with the wildcard.
+        if matches!(self.origins, AllowOrigin::Any) {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    /// Whether the method and headers requested by a preflight request are allowed.
+    fn is_request_allowed(&self, request_headers: &HeaderMap) -> bool {
+        let method_allowed = request_headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            .is_some_and(|method| self.methods.contains(&method));
+        let allowed_headers = match self.headers.as_ref() {
+            Some(allowed_headers) => allowed_headers,
+            None => return method_allowed,
+        };
+        let headers_allowed = request_headers
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|header| !header.is_empty())
+            .all(|header| {
+                HeaderName::from_bytes(header.as_bytes())
+                    .is_ok_and(|header| allowed_headers.contains(&header))
+            });
+        return method_allowed && headers_allowed;
+    }
+
+    fn preflight_headers(&self, request_headers: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let origin = request_headers
+            .get(ORIGIN)
+            .filter(|_| self.is_request_allowed(request_headers));
+        self.write_origin_headers(&mut headers, origin);
+        headers.append(
+            VARY,
+            HeaderValue::from_static(
+                "access-control-request-method, access-control-request-headers",
+            ),
+        );
+        if !headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return headers;
+        }
+        if let Some(methods) = join(self.methods.iter().map(Method::as_str)) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let allow_headers = match self.headers.as_ref() {
+            Some(allowed) => join(allowed.iter().map(HeaderName::as_str)),
+            None => request_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned(),
+        };
+        if let Some(allow_headers) = allow_headers {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = self.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age.as_secs()));
+        }
+        return headers;
+    }
+
+    fn response_headers(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        self.write_origin_headers(headers, origin);
+        if headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            if let Some(expose_headers) = join(self.expose_headers.iter().map(HeaderName::as_str)) {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for Cors<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let origin = request.headers().get(ORIGIN).cloned();
+        let preflight = Method::OPTIONS == request.method()
+            && origin.is_some()
+            && request
+                .headers()
+                .contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        if preflight {
+            let mut response = Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty().into())?;
+            *response.headers_mut() = self.preflight_headers(request.headers());
+            return Ok(response);
+        }
+        let mut response = self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await?;
+        self.response_headers(origin.as_ref(), response.headers_mut());
+        return Ok(response);
+    }
+}
+
+#[test]
+fn test_cors_headers() {
+    let cors = Cors::new(super::Router::new())
+        .allow_origin("https://a.example")
+        .unwrap()
+        .allow_methods([Method::GET, Method::PUT])
+        .expose_headers([HeaderName::from_static("x-total")])
+        .allow_credentials(true)
+        .unwrap()
+        .max_age(Duration::from_secs(600));
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert(ORIGIN, HeaderValue::from_static("https://a.example"));
+    request_headers.insert(
+        ACCESS_CONTROL_REQUEST_METHOD,
+        HeaderValue::from_static("PUT"),
+    );
+    request_headers.insert(
+        ACCESS_CONTROL_REQUEST_HEADERS,
+        HeaderValue::from_static("content-type"),
+    );
+    let headers = cors.preflight_headers(&request_headers);
+    assert_eq!("https://a.example", headers[ACCESS_CONTROL_ALLOW_ORIGIN]);
+    assert_eq!("true", headers[ACCESS_CONTROL_ALLOW_CREDENTIALS]);
+    assert_eq!("GET, PUT", headers[ACCESS_CONTROL_ALLOW_METHODS]);
+    assert_eq!("content-type", headers[ACCESS_CONTROL_ALLOW_HEADERS]);
+    assert_eq!("600", headers[ACCESS_CONTROL_MAX_AGE]);
+
+    request_headers.insert(ORIGIN, HeaderValue::from_static("https://b.example"));
+    let headers = cors.preflight_headers(&request_headers);
+    assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+
+    let mut headers = HeaderMap::new();
+    cors.response_headers(
+        Some(&HeaderValue::from_static("https://a.example")),
+        &mut headers,
+    );
+    assert_eq!("x-total", headers[ACCESS_CONTROL_EXPOSE_HEADERS]);
+    assert_eq!("origin", headers[VARY]);
+
+    let cors = Cors::new(super::Router::new()).allow_any_origin().unwrap();
+    let mut headers = HeaderMap::new();
+    cors.response_headers(
+        Some(&HeaderValue::from_static("https://b.example")),
+        &mut headers,
+    );
+    assert_eq!("*", headers[ACCESS_CONTROL_ALLOW_ORIGIN]);
+    assert!(!headers.contains_key(VARY));
+}
+
+#[test]
+fn test_cors_config() {
+    let err = Cors::new(super::Router::new())
+        .allow_any_origin()
+        .unwrap()
+        .allow_credentials(true)
+        .err();
+    assert!(matches!(err, Some(ErrNo::ConfigError(_))));
+    let err = Cors::new(super::Router::new())
+        .allow_credentials(true)
+        .unwrap()
+        .allow_any_origin()
+        .err();
+    assert!(matches!(err, Some(ErrNo::ConfigError(_))));
+    let err = Cors::new(super::Router::new())
+        .allow_origin("https://a.example\n")
+        .err();
+    assert!(matches!(err, Some(ErrNo::ConfigError(ref msg)) if msg.contains("a.example")));
+
+    // Credentials are allowed for the origins matched by a predicate.
+    let cors = Cors::new(super::Router::new())
+        .allow_any_origin()
+        .unwrap()
+        .allow_origin_fn(|origin| origin.ends_with(".a.example"))
+        .allow_credentials(true)
+        .unwrap();
+    let mut headers = HeaderMap::new();
+    cors.response_headers(
+        Some(&HeaderValue::from_static("https://www.a.example")),
+        &mut headers,
+    );
+    assert_eq!(
+        "https://www.a.example",
+        headers[ACCESS_CONTROL_ALLOW_ORIGIN]
+    );
+    assert_eq!("true", headers[ACCESS_CONTROL_ALLOW_CREDENTIALS]);
+    let mut headers = HeaderMap::new();
+    cors.response_headers(
+        Some(&HeaderValue::from_static("https://b.example")),
+        &mut headers,
+    );
+    assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_preflight() {
+    use super::TestRequest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the requests it handles.
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    #[async_trait]
+    impl HttpHandler for Counter {
+        fn namespace(&self) -> &[SharedString] {
+            &[]
+        }
+        async fn handle(
+            &self,
+            _request: Request<Body>,
+            _remote_addr: SocketAddr,
+            _request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            self.0.fetch_add(1, Ordering::AcqRel);
+            return Ok(Response::new(Body::from("handled").into()));
+        }
+    }
+
+    let cors = Cors::new(Counter::default())
+        .allow_origin("https://a.example")
+        .unwrap()
+        .allow_methods([Method::GET, Method::PUT])
+        .allow_headers([HeaderName::from_static("content-type")]);
+    let preflight = |method: &'static str, headers: &'static str| {
+        TestRequest::new(Method::OPTIONS, "/users/1")
+            .header(ORIGIN, "https://a.example")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(ACCESS_CONTROL_REQUEST_HEADERS, headers)
+    };
+
+    let response = preflight("PUT", "Content-Type").send(&cors).await.unwrap();
+    assert_eq!(StatusCode::NO_CONTENT, response.status);
+    assert!(response.body.is_empty());
+    assert_eq!(
+        "https://a.example",
+        response.headers[ACCESS_CONTROL_ALLOW_ORIGIN]
+    );
+    assert_eq!("GET, PUT", response.headers[ACCESS_CONTROL_ALLOW_METHODS]);
+    assert_eq!(
+        "content-type",
+        response.headers[ACCESS_CONTROL_ALLOW_HEADERS]
+    );
+
+    // Methods or headers not allowed are answered without CORS headers.
+    for (method, headers) in [("DELETE", "content-type"), ("PUT", "content-type, x-token")] {
+        let response = preflight(method, headers).send(&cors).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status);
+        assert!(!response.headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert!(!response.headers.contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+        assert!(!response.headers.contains_key(ACCESS_CONTROL_ALLOW_HEADERS));
+    }
+    // Preflight requests never reach the handler.
+    assert_eq!(0, cors.handler.0.load(Ordering::Acquire));
+
+    // An OPTIONS request without the preflight headers is an actual request.
+    let response = TestRequest::new(Method::OPTIONS, "/users/1")
+        .header(ORIGIN, "https://a.example")
+        .send(&cors)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("handled", response.text().unwrap());
+    assert_eq!(
+        "https://a.example",
+        response.headers[ACCESS_CONTROL_ALLOW_ORIGIN]
+    );
+    assert_eq!(1, cors.handler.0.load(Ordering::Acquire));
+}
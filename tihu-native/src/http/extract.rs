@@ -0,0 +1,469 @@
+use super::compression::{read_body_decompressed, Encoding};
+use super::read_body_limited;
+use super::router::prefix_len;
+use super::Body;
+use super::FromBody;
+use super::FromRequest;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::Request;
+use percent_encoding::percent_decode_str;
+use serde::de::value::{BorrowedStrDeserializer, MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, Unexpected, Visitor};
+use serde::forward_to_deserialize_any;
+use std::fmt::Display;
+use std::net::SocketAddr;
+
+/// Default of [`BodyLimit`], 2 MiB.
+pub const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Maximum size in bytes of the body read by the body extractors, insert it into
+/// [`RequestData`] before extracting to override [`DEFAULT_BODY_LIMIT`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BodyLimit(pub usize);
+
+impl Default for BodyLimit {
+    fn default() -> Self {
+        BodyLimit(DEFAULT_BODY_LIMIT)
+    }
+}
+
+/// The whole request body, read at most once and cached for the other body extractors.
+///
+/// A body with a `Content-Encoding` is decompressed, the [`BodyLimit`] applies to the
+/// decompressed data.
+#[derive(Clone, Debug)]
+pub struct RawBytes(pub Bytes);
+
+#[async_trait]
+impl FromBody for RawBytes {
+    async fn try_extract_body(
+        request: &mut Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let BodyLimit(limit) = request_data.get::<BodyLimit>().cloned().unwrap_or_default();
+        let content_length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<usize>().ok());
+        if let Some(content_length) = content_length {
+            if limit < content_length {
+                return Err(ErrNo::PayloadTooLarge(limit).into());
+            }
+        }
+        let encoding = match request.headers().get(CONTENT_ENCODING) {
+            Some(value) => {
+                let value = value.to_str().map_err(|_| ErrNo::ParamFormatError)?;
+                Encoding::parse(value)?
+            }
+            None => None,
+        };
+        let bytes = match encoding {
+            Some(encoding) => read_body_decompressed(request.body_mut(), encoding, limit).await?,
+            None => read_body_limited(request.body_mut(), limit).await?,
+        };
+        return Ok(RawBytes(bytes));
+    }
+}
+
+/// Request body deserialized from JSON.
+#[derive(Clone, Debug)]
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T> FromBody for Json<T>
+where
+    T: DeserializeOwned + Sync + Send + 'static,
+{
+    async fn try_extract_body(
+        request: &mut Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let RawBytes(bytes) = request_data
+            .try_get_body::<RawBytes>(request, remote_addr)
+            .await?;
+        let data = serde_json::from_slice(bytes).map_err(|_| ErrNo::ParamFormatError)?;
+        return Ok(Json(data));
+    }
+}
+
+/// Request body deserialized from `application/x-www-form-urlencoded`.
+#[derive(Clone, Debug)]
+pub struct Form<T>(pub T);
+
+#[async_trait]
+impl<T> FromBody for Form<T>
+where
+    T: DeserializeOwned + Sync + Send + 'static,
+{
+    async fn try_extract_body(
+        request: &mut Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let RawBytes(bytes) = request_data
+            .try_get_body::<RawBytes>(request, remote_addr)
+            .await?;
+        let data = serde_html_form::from_bytes(bytes).map_err(|_| ErrNo::ParamFormatError)?;
+        return Ok(Form(data));
+    }
+}
+
+/// The request path matched by the router for the current handler, stored in [`RequestData`]
+/// by [`super::Router`] before calling the handler.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MatchedPrefix(pub String);
+
+/// Query string of the request uri, percent-decoded, repeated keys can be deserialized into
+/// a `Vec`.
+#[derive(Clone, Debug)]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest for Query<T>
+where
+    T: DeserializeOwned + Sync + Send + 'static,
+{
+    async fn try_extract(
+        request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let query = request.uri().query().unwrap_or_default();
+        return Ok(Query(deserialize_query(query)?));
+    }
+}
+
+/// Percent-decoded path segments after the [`MatchedPrefix`], deserialized in order into a
+/// single value, a tuple, a sequence or the fields of a struct.
+#[derive(Clone, Debug)]
+pub struct PathParams<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest for PathParams<T>
+where
+    T: DeserializeOwned + Sync + Send + 'static,
+{
+    async fn try_extract(
+        request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let path = request.uri().path();
+        let prefix = request_data
+            .get::<MatchedPrefix>()
+            .map(|prefix| prefix.0.as_str());
+        let segments = path[prefix_len(path, prefix)..]
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                percent_decode_str(segment)
+                    .decode_utf8()
+                    .map(|segment| segment.into_owned())
+            })
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|_| ErrNo::Utf8Only)?;
+        return Ok(PathParams(deserialize_segments(&segments)?));
+    }
+}
+
+fn param_invalid<E>(err: serde_path_to_error::Error<E>) -> ErrNo
+where
+    E: Display,
+{
+    let path = err.path().to_string();
+    if "." == path {
+        return ErrNo::ParamInvalid(err.inner().to_string().into());
+    } else {
+        return ErrNo::ParamInvalid(format!("{}: {}", path, err.inner()).into());
+    }
+}
+
+pub(crate) fn deserialize_query<T>(query: &str) -> Result<T, ErrNo>
+where
+    T: DeserializeOwned,
+{
+    let deserializer = serde_html_form::Deserializer::from_bytes(query.as_bytes());
+    return serde_path_to_error::deserialize(deserializer).map_err(param_invalid);
+}
+
+pub(crate) fn deserialize_segments<T>(segments: &[String]) -> Result<T, ErrNo>
+where
+    T: DeserializeOwned,
+{
+    return serde_path_to_error::deserialize(PathDeserializer(segments)).map_err(param_invalid);
+}
+
+struct PathDeserializer<'de>(&'de [String]);
+
+macro_rules! single_segment {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                match self.0 {
+                    [segment] => SegmentDeserializer(segment).$method(visitor),
+                    _ => Err(de::Error::invalid_length(self.0.len(), &"1")),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for PathDeserializer<'de> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let segments = self.0.iter().map(|segment| SegmentDeserializer(segment));
+        visitor.visit_seq(SeqDeserializer::new(segments))
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let segments = self.0.iter().map(|segment| SegmentDeserializer(segment));
+        visitor.visit_map(MapDeserializer::new(fields.iter().copied().zip(segments)))
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.0 {
+            [segment] => SegmentDeserializer(segment).deserialize_enum(name, variants, visitor),
+            _ => Err(de::Error::invalid_length(self.0.len(), &"1")),
+        }
+    }
+
+    single_segment! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_char deserialize_str deserialize_string deserialize_option
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct seq tuple tuple_struct map identifier
+        ignored_any
+    }
+}
+
+struct SegmentDeserializer<'de>(&'de str);
+
+macro_rules! parse_segment {
+    ($($method:ident => $visit:ident)*) => {
+        $(
+            fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>,
+            {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(de::Error::invalid_value(Unexpected::Str(self.0), &visitor)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for SegmentDeserializer<'de> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_enum(BorrowedStrDeserializer::new(self.0))
+    }
+
+    parse_segment! {
+        deserialize_bool => visit_bool
+        deserialize_i8 => visit_i8
+        deserialize_i16 => visit_i16
+        deserialize_i32 => visit_i32
+        deserialize_i64 => visit_i64
+        deserialize_u8 => visit_u8
+        deserialize_u16 => visit_u16
+        deserialize_u32 => visit_u32
+        deserialize_u64 => visit_u64
+        deserialize_f32 => visit_f32
+        deserialize_f64 => visit_f64
+        deserialize_char => visit_char
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, de::value::Error> for SegmentDeserializer<'de> {
+    type Deserializer = SegmentDeserializer<'de>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[test]
+fn test_deserialize_params() {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Filter {
+        id: u64,
+        #[serde(default)]
+        tag: Vec<String>,
+        name: Option<String>,
+    }
+    let filter: Filter = deserialize_query("id=1&tag=a&tag=b%20c&tag=d+e").unwrap();
+    assert_eq!(
+        Filter {
+            id: 1,
+            tag: vec!["a".into(), "b c".into(), "d e".into()],
+            name: None,
+        },
+        filter
+    );
+    let err = deserialize_query::<Filter>("id=x").unwrap_err();
+    assert!(matches!(err, ErrNo::ParamInvalid(ref msg) if msg.starts_with("id")));
+    let err = deserialize_query::<Filter>("name=a").unwrap_err();
+    assert!(matches!(err, ErrNo::ParamInvalid(ref msg) if msg.contains("`id`")));
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Article {
+        id: u64,
+        action: String,
+    }
+    let segments = vec!["7".to_string(), "a b".to_string()];
+    assert_eq!(
+        (7, "a b".to_string()),
+        deserialize_segments::<(u64, String)>(&segments).unwrap()
+    );
+    assert_eq!(
+        Article {
+            id: 7,
+            action: "a b".into(),
+        },
+        deserialize_segments::<Article>(&segments).unwrap()
+    );
+    assert_eq!(7, deserialize_segments::<u64>(&segments[..1]).unwrap());
+    deserialize_segments::<u64>(&segments).expect_err("expected one segment");
+    let err = deserialize_segments::<Article>(&segments[1..]).unwrap_err();
+    assert!(matches!(err, ErrNo::ParamInvalid(ref msg) if msg.starts_with("id")));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_body_extractors() {
+    use serde::Deserialize;
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Login {
+        id: u64,
+        name: String,
+    }
+
+    async fn extract<T>(body: &'static str, content_length: bool, limit: usize) -> Result<T, ErrNo>
+    where
+        T: FromBody,
+    {
+        let mut request = Request::post("/login");
+        if content_length {
+            request = request.header(CONTENT_LENGTH, body.len());
+        }
+        let mut request = request.body(Body::from(body)).unwrap();
+        let mut request_data = RequestData::new();
+        request_data.insert(BodyLimit(limit));
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        return T::try_extract_body(&mut request, remote_addr, &mut request_data)
+            .await
+            .map_err(|err| err.downcast::<ErrNo>().unwrap());
+    }
+
+    let expected = Login {
+        id: 1,
+        name: "a b".into(),
+    };
+    let Json(login) = extract::<Json<Login>>(r#"{"id":1,"name":"a b"}"#, true, 64)
+        .await
+        .unwrap();
+    assert_eq!(expected, login);
+    let Form(login) = extract::<Form<Login>>("id=1&name=a+b", false, 64)
+        .await
+        .unwrap();
+    assert_eq!(expected, login);
+    let err = extract::<Json<Login>>(r#"{"id":1,"name":"#, true, 64).await;
+    assert!(matches!(err, Err(ErrNo::ParamFormatError)));
+    let err = extract::<Form<Login>>("id=x&name=a", true, 64).await;
+    assert!(matches!(err, Err(ErrNo::ParamFormatError)));
+
+    // Rejected from the Content-Length, or while reading a body without it.
+    let body = r#"{"id":1,"name":"a b"}"#;
+    let err = extract::<Json<Login>>(body, true, 8).await;
+    assert!(matches!(err, Err(ErrNo::PayloadTooLarge(8))));
+    let err = extract::<Json<Login>>(body, false, 8).await;
+    assert!(matches!(err, Err(ErrNo::PayloadTooLarge(8))));
+}
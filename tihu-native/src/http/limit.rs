@@ -0,0 +1,364 @@
+use super::err_no_response;
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::{Request, Response};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tihu::SharedString;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Respond [`ErrNo::Timeout`] when the wrapped handler does not respond within the deadline.
+///
+/// The deadline covers producing the response, not sending its body.
+pub struct Timeout<H> {
+    handler: H,
+    timeout: Duration,
+}
+
+impl<H> Timeout<H>
+where
+    H: HttpHandler,
+{
+    pub fn new(handler: H, timeout: Duration) -> Timeout<H> {
+        Timeout {
+            handler: handler,
+            timeout: timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for Timeout<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let path = request.uri().path().to_string();
+        let result = tokio::time::timeout(
+            self.timeout,
+            self.handler
+                .handle(request, remote_addr, request_data, prefix),
+        )
+        .await;
+        match result {
+            Ok(result) => return result,
+            Err(_) => {
+                return err_no_response(ErrNo::Timeout(format!("处理请求{}", path).into()));
+            }
+        }
+    }
+}
+
+/// Cap the requests handled at once by the wrapped handler, responding
+/// [`ErrNo::ServiceBusy`] to the requests over the limit.
+///
+/// By default requests over the limit are rejected at once, with a queue they wait for a
+/// request to complete first. The limit covers producing the responses, not sending their
+/// bodies.
+pub struct ConcurrencyLimit<H> {
+    handler: H,
+    semaphore: Arc<Semaphore>,
+    name: SharedString,
+    max_waiting: usize,
+    waiting: AtomicUsize,
+    queue_timeout: Option<Duration>,
+}
+
+impl<H> ConcurrencyLimit<H>
+where
+    H: HttpHandler,
+{
+    pub fn new(handler: H, max_concurrency: usize) -> ConcurrencyLimit<H> {
+        let name = handler
+            .namespace()
+            .iter()
+            .map(|segment| segment.as_ref())
+            .collect::<Vec<&str>>()
+            .join("/");
+        ConcurrencyLimit {
+            handler: handler,
+            semaphore: Arc::new(Semaphore::new(max_concurrency)),
+            name: name.into(),
+            max_waiting: 0,
+            waiting: AtomicUsize::new(0),
+            queue_timeout: None,
+        }
+    }
+
+    /// Set the service name of the [`ErrNo::ServiceBusy`] error, the handler namespace by
+    /// default.
+    pub fn name<N>(mut self, name: N) -> ConcurrencyLimit<H>
+    where
+        N: Into<SharedString>,
+    {
+        self.name = name.into();
+        return self;
+    }
+
+    /// Let up to `max_waiting` requests wait for a slot, for at most `timeout` when given.
+    pub fn queue(mut self, max_waiting: usize, timeout: Option<Duration>) -> ConcurrencyLimit<H> {
+        self.max_waiting = max_waiting;
+        self.queue_timeout = timeout;
+        return self;
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, ErrNo> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let busy = || ErrNo::ServiceBusy(self.name.clone());
+        let queued = self
+            .waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < self.max_waiting).then_some(waiting + 1)
+            });
+        if queued.is_err() {
+            return Err(busy());
+        }
+        let _waiting = WaitingGuard(&self.waiting);
+        let acquire = self.semaphore.clone().acquire_owned();
+        let permit = match self.queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire)
+                .await
+                .ok()
+                .and_then(Result::ok),
+            None => acquire.await.ok(),
+        };
+        return permit.ok_or_else(busy);
+    }
+}
+
+/// Leaves the queue when dropped, so requests cancelled while waiting are not counted.
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for ConcurrencyLimit<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let _permit = match self.acquire().await {
+            Ok(permit) => permit,
+            Err(err_no) => return err_no_response(err_no),
+        };
+        return self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await;
+    }
+}
+
+/// A switch pausing the [`Pausable`] handlers it is given to, clones share the same state.
+#[derive(Clone, Default, Debug)]
+pub struct PauseSwitch {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseSwitch {
+    pub fn new() -> PauseSwitch {
+        Default::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        return self.paused.load(Ordering::Acquire);
+    }
+}
+
+/// Respond [`ErrNo::ServicePaused`] instead of calling the wrapped handler while the switch is
+/// paused, for maintenance for example.
+pub struct Pausable<H> {
+    handler: H,
+    switch: PauseSwitch,
+}
+
+impl<H> Pausable<H>
+where
+    H: HttpHandler,
+{
+    pub fn new(handler: H, switch: PauseSwitch) -> Pausable<H> {
+        Pausable {
+            handler: handler,
+            switch: switch,
+        }
+    }
+}
+
+#[async_trait]
+impl<H> HttpHandler for Pausable<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        if self.switch.is_paused() {
+            return err_no_response(ErrNo::ServicePaused);
+        }
+        return self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await;
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_concurrency_limit() {
+    let limit = ConcurrencyLimit::new(super::Router::new(), 1)
+        .name("test")
+        .queue(1, Some(Duration::from_millis(50)));
+    let permit = limit.acquire().await.unwrap();
+    let (waited, rejected) = tokio::join!(limit.acquire(), async {
+        tokio::task::yield_now().await;
+        limit.acquire().await
+    });
+    assert!(matches!(waited, Err(ErrNo::ServiceBusy(_))));
+    assert!(matches!(rejected, Err(ErrNo::ServiceBusy(_))));
+    for _ in 0..3 {
+        let cancelled = tokio::time::timeout(Duration::from_millis(5), limit.acquire()).await;
+        assert!(cancelled.is_err());
+    }
+    assert_eq!(0, limit.waiting.load(Ordering::Acquire));
+    let (waited, _) = tokio::join!(limit.acquire(), async move {
+        tokio::task::yield_now().await;
+        drop(permit);
+    });
+    assert!(waited.is_ok());
+}
+
+/// Responds `done` after sleeping, counting the requests it handles.
+#[cfg(test)]
+struct Sleep {
+    namespace: Vec<SharedString>,
+    duration: Duration,
+    handled: Arc<AtomicUsize>,
+}
+
+#[cfg(test)]
+impl Sleep {
+    fn new(duration: Duration) -> Sleep {
+        Sleep {
+            namespace: Vec::new(),
+            duration: duration,
+            handled: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl HttpHandler for Sleep {
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        _request: Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+        _prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        tokio::time::sleep(self.duration).await;
+        self.handled.fetch_add(1, Ordering::AcqRel);
+        return Ok(Response::new(Body::from("done").into()));
+    }
+}
+
+#[cfg(test)]
+#[tokio::test(start_paused = true)]
+async fn test_timeout() {
+    use super::TestRequest;
+    use hyper::StatusCode;
+    use tokio::time::Instant;
+
+    let start = Instant::now();
+    let handler = Timeout::new(Sleep::new(Duration::from_secs(5)), Duration::from_secs(1));
+    let response = TestRequest::get("/slow").send(&handler).await.unwrap();
+    assert_eq!(Duration::from_secs(1), start.elapsed());
+    assert_eq!(StatusCode::GATEWAY_TIMEOUT, response.status);
+    let response = response.api_response::<()>().unwrap();
+    let err_no = ErrNo::Timeout(SharedString::from_static("处理请求/slow"));
+    assert_eq!(err_no.code(), response.code);
+    assert_eq!(err_no.to_string(), response.message.as_ref());
+    assert_eq!(0, handler.handler.handled.load(Ordering::Acquire));
+
+    let start = Instant::now();
+    let handler = Timeout::new(Sleep::new(Duration::from_secs(1)), Duration::from_secs(5));
+    let response = TestRequest::get("/fast").send(&handler).await.unwrap();
+    assert_eq!(Duration::from_secs(1), start.elapsed());
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("done", response.text().unwrap());
+}
+
+#[cfg(test)]
+#[tokio::test(start_paused = true)]
+async fn test_pausable() {
+    use super::TestRequest;
+    use hyper::StatusCode;
+
+    let switch = PauseSwitch::new();
+    let handler = Pausable::new(Sleep::new(Duration::from_secs(1)), switch.clone());
+    // Requests already handled when paused complete, new ones are rejected at once.
+    let (handled, paused) = tokio::join!(TestRequest::get("/").send(&handler), async {
+        tokio::task::yield_now().await;
+        switch.pause();
+        TestRequest::get("/").send(&handler).await
+    });
+    assert_eq!(StatusCode::OK, handled.unwrap().status);
+    let paused = paused.unwrap();
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, paused.status);
+    let paused = paused.api_response::<()>().unwrap();
+    assert_eq!(ErrNo::ServicePaused.code(), paused.code);
+    assert_eq!(1, handler.handler.handled.load(Ordering::Acquire));
+
+    switch.resume();
+    let response = TestRequest::get("/").send(&handler).await.unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("done", response.text().unwrap());
+    assert_eq!(2, handler.handler.handled.load(Ordering::Acquire));
+}
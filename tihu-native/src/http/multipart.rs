@@ -0,0 +1,240 @@
+use super::Body;
+use super::RequestData;
+use crate::ErrNo;
+use bytes::Bytes;
+use futures::Stream;
+use http_body_util::BodyExt;
+use hyper::header::CONTENT_TYPE;
+use hyper::{HeaderMap, Request};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Size limits in bytes of a multipart body, insert it into [`RequestData`] before extracting
+/// [`Multipart`] to override the default.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MultipartLimit {
+    /// Limit of the whole body.
+    pub total: usize,
+    /// Limit of each field, including files.
+    pub per_field: usize,
+}
+
+impl Default for MultipartLimit {
+    fn default() -> Self {
+        MultipartLimit {
+            total: 64 * 1024 * 1024,
+            per_field: 32 * 1024 * 1024,
+        }
+    }
+}
+
+fn multipart_error(err: multer::Error) -> ErrNo {
+    match err {
+        multer::Error::FieldSizeExceeded { limit, .. } => {
+            return ErrNo::PayloadTooLarge(limit as usize);
+        }
+        multer::Error::StreamSizeExceeded { limit } => {
+            return ErrNo::PayloadTooLarge(limit as usize);
+        }
+        multer::Error::StreamReadFailed(_) => {
+            return ErrNo::Other(err.into());
+        }
+        _ => {
+            return ErrNo::MultipartRequired;
+        }
+    }
+}
+
+/// A `multipart/form-data` body, parsed while it is read, so files are never buffered as a
+/// whole.
+pub struct Multipart<'r> {
+    inner: multer::Multipart<'r>,
+}
+
+impl<'r> Multipart<'r> {
+    /// Start parsing the body of `request`, returning [`ErrNo::MultipartRequired`] when it is
+    /// not `multipart/form-data`. Limits are taken from the [`MultipartLimit`] in
+    /// `request_data`.
+    pub fn try_extract(
+        request: &'r mut Request<Body>,
+        request_data: &RequestData,
+    ) -> Result<Multipart<'r>, ErrNo> {
+        let limit = request_data
+            .get::<MultipartLimit>()
+            .cloned()
+            .unwrap_or_default();
+        let boundary = request
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| multer::parse_boundary(value).ok())
+            .ok_or(ErrNo::MultipartRequired)?;
+        let constraints = multer::Constraints::new().size_limit(
+            multer::SizeLimit::new()
+                .whole_stream(limit.total as u64)
+                .per_field(limit.per_field as u64),
+        );
+        let stream = request.body_mut().into_data_stream();
+        return Ok(Multipart {
+            inner: multer::Multipart::with_constraints(stream, boundary, constraints),
+        });
+    }
+
+    /// The next field, a field must be consumed or dropped before reading the next one.
+    pub async fn next_field(&mut self) -> Result<Option<Field<'r>>, ErrNo> {
+        let field = self.inner.next_field().await.map_err(multipart_error)?;
+        return Ok(field.map(|inner| Field { inner: inner }));
+    }
+}
+
+/// A field of a [`Multipart`] body, it is a stream of the field data chunks.
+pub struct Field<'r> {
+    inner: multer::Field<'r>,
+}
+
+impl Field<'_> {
+    pub fn name(&self) -> Option<&str> {
+        self.inner.name()
+    }
+
+    /// The file name, only present for file fields.
+    pub fn file_name(&self) -> Option<&str> {
+        self.inner.file_name()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.inner.content_type().map(|mime| mime.essence_str())
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        self.inner.headers()
+    }
+
+    /// Read the whole field data into memory.
+    pub async fn bytes(self) -> Result<Bytes, ErrNo> {
+        return self.inner.bytes().await.map_err(multipart_error);
+    }
+
+    /// Read the whole field data as text.
+    pub async fn text(self) -> Result<String, ErrNo> {
+        return self.inner.text().await.map_err(multipart_error);
+    }
+}
+
+impl Stream for Field<'_> {
+    type Item = Result<Bytes, ErrNo>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|item| item.map(|chunk| chunk.map_err(multipart_error)))
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_multipart() {
+    use crate::http::{err_no_response, BoxBody, HttpHandler, TestRequest};
+    use async_trait::async_trait;
+    use hyper::{Response, StatusCode};
+    use std::net::SocketAddr;
+    use tihu::SharedString;
+
+    const BOUNDARY: &str = "X-TIHU-BOUNDARY";
+
+    /// Responds `name:file name:size` of each field.
+    struct Upload {
+        namespace: Vec<SharedString>,
+        limit: Option<MultipartLimit>,
+    }
+
+    impl Upload {
+        async fn fields(
+            &self,
+            mut request: Request<Body>,
+            request_data: &mut RequestData,
+        ) -> Result<Vec<String>, ErrNo> {
+            if let Some(limit) = self.limit {
+                request_data.insert(limit);
+            }
+            let mut multipart = Multipart::try_extract(&mut request, request_data)?;
+            let mut fields = Vec::new();
+            while let Some(field) = multipart.next_field().await? {
+                let name = field.name().unwrap_or_default().to_string();
+                let file_name = field.file_name().unwrap_or_default().to_string();
+                let data = field.bytes().await?;
+                fields.push(format!("{}:{}:{}", name, file_name, data.len()));
+            }
+            return Ok(fields);
+        }
+    }
+
+    #[async_trait]
+    impl HttpHandler for Upload {
+        fn namespace(&self) -> &[SharedString] {
+            &self.namespace
+        }
+        async fn handle(
+            &self,
+            request: Request<Body>,
+            _remote_addr: SocketAddr,
+            request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            match self.fields(request, request_data).await {
+                Ok(fields) => return Ok(Response::new(Body::from(fields.join(",")).into())),
+                Err(err_no) => return err_no_response(err_no),
+            }
+        }
+    }
+
+    fn upload(limit: Option<MultipartLimit>) -> Upload {
+        Upload {
+            namespace: vec![SharedString::from_static("upload")],
+            limit: limit,
+        }
+    }
+
+    fn form(file: &str) -> String {
+        return format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n{file}\r\n--{b}--\r\n",
+            b = BOUNDARY,
+            file = file
+        );
+    }
+
+    async fn send(handler: &Upload, content_type: &str, body: String) -> (StatusCode, String) {
+        let response = TestRequest::post("/upload")
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .send(handler)
+            .await
+            .unwrap();
+        return (response.status, response.text().unwrap().to_string());
+    }
+
+    let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+    let (status, text) = send(&upload(None), &content_type, form("0123456789")).await;
+    assert_eq!(StatusCode::OK, status);
+    assert_eq!("title::5,file:a.txt:10", text);
+
+    let (status, _) = send(&upload(None), "application/json", form("0123456789")).await;
+    assert_eq!(ErrNo::MultipartRequired.status_code(), status);
+    let (status, text) = send(&upload(None), &content_type, "garbage".to_string()).await;
+    assert_eq!(ErrNo::MultipartRequired.status_code(), status);
+    assert!(text.contains(&ErrNo::MultipartRequired.code().to_string()));
+
+    let per_field = MultipartLimit {
+        total: 1024,
+        per_field: 8,
+    };
+    let (status, _) = send(&upload(Some(per_field)), &content_type, form("0123456789")).await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+    let total = MultipartLimit {
+        total: 64,
+        per_field: 1024,
+    };
+    let (status, _) = send(&upload(Some(total)), &content_type, form("0123456789")).await;
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, status);
+}
@@ -0,0 +1,223 @@
+use super::Body;
+use super::BoxBody;
+use super::FromBody;
+use super::FromRequest;
+use super::RawBytes;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE, VARY};
+use hyper::{HeaderMap, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::SocketAddr;
+
+/// Root element of the XML documents.
+pub const XML_ROOT: &str = "response";
+
+/// A serialization format of request and response bodies.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    /// XML, with a [`XML_ROOT`] root element holding the fields as child elements.
+    Xml,
+    /// MessagePack, structs are encoded as maps so fields are matched by name.
+    MessagePack,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => return "application/json; charset=utf-8",
+            Format::Xml => return "application/xml; charset=utf-8",
+            Format::MessagePack => return "application/msgpack",
+        }
+    }
+
+    /// The format of a media type, parameters are ignored.
+    pub fn from_media_type(media_type: &str) -> Option<Format> {
+        let media_type = media_type.split(';').next().unwrap_or_default().trim();
+        let media_type = media_type.to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/json" => return Some(Format::Json),
+            "application/xml" | "text/xml" => return Some(Format::Xml),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                return Some(Format::MessagePack);
+            }
+            _ if media_type.ends_with("+json") => return Some(Format::Json),
+            _ if media_type.ends_with("+xml") => return Some(Format::Xml),
+            _ => return None,
+        }
+    }
+
+    /// The format of a request body according to its `Content-Type`, JSON when missing or
+    /// unknown, so clients not setting it keep working.
+    pub fn from_content_type(headers: &HeaderMap) -> Format {
+        return headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(Format::from_media_type)
+            .unwrap_or_default();
+    }
+
+    /// The format preferred by the `Accept` header, by quality then by order. JSON when no
+    /// supported format is accepted.
+    pub fn from_accept(headers: &HeaderMap) -> Format {
+        let mut preferred: Option<(Format, f32)> = None;
+        let ranges = headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for range in ranges {
+            let mut params = range.split(';');
+            let format = match Format::from_media_type(params.next().unwrap_or_default()) {
+                Some(format) => format,
+                None => continue,
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .filter_map(|quality| quality.parse::<f32>().ok())
+                .next()
+                .unwrap_or(1.0);
+            if 0.0 < quality && preferred.is_none_or(|(_, preferred)| preferred < quality) {
+                preferred = Some((format, quality));
+            }
+        }
+        return preferred.map(|(format, _)| format).unwrap_or_default();
+    }
+
+    pub fn encode<T>(&self, data: &T) -> Result<Vec<u8>, anyhow::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        match self {
+            Format::Json => return Ok(serde_json::to_vec(data).map_err(ErrNo::SerializeError)?),
+            Format::Xml => {
+                let xml = quick_xml::se::to_string_with_root(XML_ROOT, data)?;
+                return Ok(xml.into_bytes());
+            }
+            Format::MessagePack => return Ok(rmp_serde::to_vec_named(data)?),
+        }
+    }
+
+    /// Deserialize `data`, failing with [`ErrNo::ParamFormatError`].
+    pub fn decode<T>(&self, data: &[u8]) -> Result<T, ErrNo>
+    where
+        T: DeserializeOwned,
+    {
+        let result = match self {
+            Format::Json => serde_json::from_slice(data).map_err(anyhow::Error::from),
+            Format::Xml => std::str::from_utf8(data)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| quick_xml::de::from_str(data).map_err(anyhow::Error::from)),
+            Format::MessagePack => rmp_serde::from_slice(data).map_err(anyhow::Error::from),
+        };
+        return result.map_err(|_| ErrNo::ParamFormatError);
+    }
+
+    /// Build a response with the given status whose body is `data` in this format.
+    pub fn response<T>(
+        &self,
+        status: StatusCode,
+        data: &T,
+    ) -> Result<Response<BoxBody>, anyhow::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let body = self.encode(data)?;
+        let response = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, self.content_type())
+            .header(VARY, HeaderValue::from_static("accept"))
+            .body(Body::from(body).into())?;
+        return Ok(response);
+    }
+}
+
+/// The response format preferred by the client, see [`Format::from_accept`].
+#[async_trait]
+impl FromRequest for Format {
+    async fn try_extract(
+        request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        return Ok(Format::from_accept(request.headers()));
+    }
+}
+
+/// Request body deserialized from the format of its `Content-Type`, see
+/// [`Format::from_content_type`].
+#[derive(Clone, Debug)]
+pub struct Negotiated<T>(pub T);
+
+#[async_trait]
+impl<T> FromBody for Negotiated<T>
+where
+    T: DeserializeOwned + Sync + Send + 'static,
+{
+    async fn try_extract_body(
+        request: &mut Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let format = Format::from_content_type(request.headers());
+        let RawBytes(bytes) = request_data
+            .try_get_body::<RawBytes>(request, remote_addr)
+            .await?;
+        return Ok(Negotiated(format.decode(bytes)?));
+    }
+}
+
+#[test]
+fn test_formats() {
+    use tihu::api::Response;
+
+    #[derive(Serialize, serde::Deserialize, PartialEq, Debug)]
+    struct User {
+        id: u64,
+        name: String,
+    }
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(Format::Json, Format::from_accept(&headers));
+    headers.insert(
+        ACCEPT,
+        HeaderValue::from_static(
+            "text/html, application/xml;q=0.9, application/msgpack;q=0.8, */*;q=0.1",
+        ),
+    );
+    assert_eq!(Format::Xml, Format::from_accept(&headers));
+    headers.insert(
+        ACCEPT,
+        HeaderValue::from_static("application/xml;q=0, application/x-msgpack"),
+    );
+    assert_eq!(Format::MessagePack, Format::from_accept(&headers));
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/xml; charset=utf-8"),
+    );
+    assert_eq!(Format::Xml, Format::from_content_type(&headers));
+
+    let user = User {
+        id: 7,
+        name: "a & b".to_string(),
+    };
+    for format in [Format::Json, Format::Xml, Format::MessagePack] {
+        let encoded = format.encode(&Response::success(Some(&user))).unwrap();
+        let decoded: Response<User> = format.decode(&encoded).unwrap();
+        assert_eq!(0, decoded.code);
+        assert_eq!(Some(&user), decoded.data.as_ref());
+    }
+    let xml = Format::Xml.encode(&Response::success(Some(&user))).unwrap();
+    assert_eq!(
+        "<response><code>0</code><data><id>7</id><name>a &amp; b</name></data><message>success</message></response>",
+        String::from_utf8(xml).unwrap()
+    );
+    assert!(matches!(
+        Format::MessagePack.decode::<User>(b"{}"),
+        Err(ErrNo::ParamFormatError)
+    ));
+}
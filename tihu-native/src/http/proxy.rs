@@ -0,0 +1,523 @@
+use super::err_no_response;
+use super::router::prefix_len;
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::body::{Frame, Incoming, SizeHint};
+use hyper::header::{
+    HeaderName, HeaderValue, CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE,
+    TRAILER, TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::{HeaderMap, Request, Response, Uri};
+use hyper_util::client::legacy::connect::{Connect, HttpConnector};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use pin_project::pin_project;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tihu::SharedString;
+
+/// Consecutive failures marking an upstream down by default.
+pub const DEFAULT_MAX_FAILURES: usize = 3;
+/// Time an upstream stays down by default, before being tried again.
+pub const DEFAULT_FAIL_TIMEOUT: Duration = Duration::from_secs(10);
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// How [`ReverseProxy`] spreads requests over the upstreams up.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LoadBalance {
+    RoundRobin,
+    /// The upstream with the fewest requests in progress, including sending their response
+    /// bodies.
+    LeastConnections,
+}
+
+struct Upstream {
+    base_url: SharedString,
+    active: AtomicUsize,
+    failures: AtomicUsize,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    /// The time the upstream is down until, a panic while holding the lock leaves it valid.
+    fn down_until(&self) -> MutexGuard<'_, Option<Instant>> {
+        return self
+            .down_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+
+    fn is_up(&self, now: Instant) -> bool {
+        return self.down_until().is_none_or(|down_until| down_until <= now);
+    }
+
+    fn succeed(&self) {
+        self.failures.store(0, Ordering::Release);
+        self.down_until().take();
+    }
+
+    fn fail(&self, max_failures: usize, fail_timeout: Duration) {
+        let failures = self.failures.fetch_add(1, Ordering::AcqRel) + 1;
+        if failures >= max_failures {
+            self.failures.store(0, Ordering::Release);
+            self.down_until().replace(Instant::now() + fail_timeout);
+        }
+    }
+}
+
+/// Counts a request in progress on an upstream while alive.
+struct ActiveGuard(Arc<Upstream>);
+
+impl ActiveGuard {
+    fn new(upstream: Arc<Upstream>) -> ActiveGuard {
+        upstream.active.fetch_add(1, Ordering::AcqRel);
+        return ActiveGuard(upstream);
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Forward the requests under `namespace` to a set of upstream base urls, like
+/// `http://10.0.0.1:8080/api`, streaming the bodies both ways.
+///
+/// The path below the namespace is appended to the base url, the query is kept. Hop-by-hop
+/// headers are removed, `Host` is set to the upstream, and `X-Forwarded-For`,
+/// `X-Forwarded-Host` and `X-Forwarded-Proto` are added. Upgrade requests are not proxied.
+///
+/// Upstreams failing to respond are marked down after a few consecutive failures, and tried
+/// again after a while. A failed request is retried once on another upstream up when it is
+/// idempotent and has no body, as a streamed body cannot be sent again. [`ErrNo::NoService`]
+/// is responded when no upstream is up, or the upstreams tried fail.
+pub struct ReverseProxy<C = HttpConnector> {
+    namespace: Vec<SharedString>,
+    name: SharedString,
+    upstreams: Vec<Arc<Upstream>>,
+    load_balance: LoadBalance,
+    next: AtomicUsize,
+    max_failures: usize,
+    fail_timeout: Duration,
+    preserve_host: bool,
+    client: Client<C, Body>,
+}
+
+impl ReverseProxy {
+    pub fn new<I, U>(namespace: Vec<SharedString>, upstreams: I) -> ReverseProxy
+    where
+        I: IntoIterator<Item = U>,
+        U: Into<SharedString>,
+    {
+        let client = Client::builder(TokioExecutor::new()).build_http();
+        return ReverseProxy::with_client(namespace, upstreams, client);
+    }
+}
+
+impl<C> ReverseProxy<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Create a proxy on a preconfigured hyper client, for example one with a TLS connector.
+    pub fn with_client<I, U>(
+        namespace: Vec<SharedString>,
+        upstreams: I,
+        client: Client<C, Body>,
+    ) -> ReverseProxy<C>
+    where
+        I: IntoIterator<Item = U>,
+        U: Into<SharedString>,
+    {
+        let name = namespace
+            .iter()
+            .map(|segment| segment.as_ref())
+            .collect::<Vec<&str>>()
+            .join("/");
+        let upstreams = upstreams
+            .into_iter()
+            .map(|base_url| {
+                Arc::new(Upstream {
+                    base_url: base_url.into(),
+                    active: AtomicUsize::new(0),
+                    failures: AtomicUsize::new(0),
+                    down_until: Mutex::new(None),
+                })
+            })
+            .collect();
+        ReverseProxy {
+            namespace: namespace,
+            name: name.into(),
+            upstreams: upstreams,
+            load_balance: LoadBalance::RoundRobin,
+            next: AtomicUsize::new(0),
+            max_failures: DEFAULT_MAX_FAILURES,
+            fail_timeout: DEFAULT_FAIL_TIMEOUT,
+            preserve_host: false,
+            client: client,
+        }
+    }
+
+    /// Set the service name of the [`ErrNo::NoService`] error, the namespace by default.
+    pub fn name<N>(mut self, name: N) -> ReverseProxy<C>
+    where
+        N: Into<SharedString>,
+    {
+        self.name = name.into();
+        return self;
+    }
+
+    /// Set the load balancing, round-robin by default.
+    pub fn load_balance(mut self, load_balance: LoadBalance) -> ReverseProxy<C> {
+        self.load_balance = load_balance;
+        return self;
+    }
+
+    /// Mark an upstream down for `fail_timeout` after `max_failures` consecutive failures.
+    pub fn health_check(mut self, max_failures: usize, fail_timeout: Duration) -> ReverseProxy<C> {
+        self.max_failures = max_failures.max(1);
+        self.fail_timeout = fail_timeout;
+        return self;
+    }
+
+    /// Forward the `Host` header of the client instead of the upstream one.
+    pub fn preserve_host(mut self, preserve_host: bool) -> ReverseProxy<C> {
+        self.preserve_host = preserve_host;
+        return self;
+    }
+
+    fn select(&self) -> Option<Arc<Upstream>> {
+        let now = Instant::now();
+        let count = self.upstreams.len();
+        if 0 == count {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut up = (0..count)
+            .map(|offset| &self.upstreams[(start + offset) % count])
+            .filter(|upstream| upstream.is_up(now));
+        let upstream = match self.load_balance {
+            LoadBalance::RoundRobin => up.next(),
+            // Ties go to the round-robin order, so idle upstreams share the load.
+            LoadBalance::LeastConnections => {
+                up.min_by_key(|upstream| upstream.active.load(Ordering::Acquire))
+            }
+        };
+        return upstream.cloned();
+    }
+
+    fn upstream_uri(
+        &self,
+        upstream: &Upstream,
+        uri: &Uri,
+        prefix: Option<&str>,
+    ) -> Result<Uri, ErrNo> {
+        let path = uri.path();
+        let rest = path[prefix_len(path, prefix)..].trim_start_matches('/');
+        let mut url = format!("{}/{}", upstream.base_url.trim_end_matches('/'), rest);
+        if let Some(query) = uri.query() {
+            url.push('?');
+            url.push_str(query);
+        }
+        return url
+            .parse()
+            .map_err(|_| ErrNo::ConfigError(format!("上游地址\"{}\"不正确", url).into()));
+    }
+
+    fn upstream_request(
+        &self,
+        upstream: &Upstream,
+        parts: &hyper::http::request::Parts,
+        body: Body,
+        remote_addr: SocketAddr,
+        prefix: Option<&str>,
+    ) -> Result<Request<Body>, ErrNo> {
+        let uri = self.upstream_uri(upstream, &parts.uri, prefix)?;
+        let upstream_host = if self.preserve_host {
+            None
+        } else {
+            uri.authority()
+                .map(|authority| authority.as_str().to_string())
+        };
+        let mut request = Request::new(body);
+        *request.method_mut() = parts.method.clone();
+        *request.headers_mut() = parts.headers.clone();
+        forward_headers(request.headers_mut(), remote_addr, upstream_host.as_deref());
+        // hyper picks the protocol of the upstream connection itself, HTTP/1.1 is the default.
+        *request.uri_mut() = uri;
+        return Ok(request);
+    }
+}
+
+/// Remove the hop-by-hop headers, including the ones listed by `Connection`.
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in [
+        CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+}
+
+fn forward_headers(headers: &mut HeaderMap, remote_addr: SocketAddr, upstream_host: Option<&str>) {
+    remove_hop_by_hop(headers);
+    let forwarded_for = match headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(forwarded_for) => format!("{}, {}", forwarded_for, remote_addr.ip()),
+        None => remote_addr.ip().to_string(),
+    };
+    if let Ok(forwarded_for) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, forwarded_for);
+    }
+    // Values set by a proxy in front, terminating TLS for example, are kept.
+    if !headers.contains_key(X_FORWARDED_HOST) {
+        if let Some(host) = headers.get(HOST).cloned() {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+    }
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("http"));
+    }
+    if let Some(upstream_host) = upstream_host.and_then(|host| HeaderValue::from_str(host).ok()) {
+        headers.insert(HOST, upstream_host);
+    }
+}
+
+#[async_trait]
+impl<C> HttpHandler for ReverseProxy<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let mut upstream = match self.select() {
+            Some(upstream) => upstream,
+            None => return err_no_response(ErrNo::NoService(self.name.clone())),
+        };
+        let (parts, body) = request.into_parts();
+        let mut retry = parts.method.is_idempotent() && hyper::body::Body::is_end_stream(&body);
+        let mut body = Some(body);
+        let (response, guard) = loop {
+            let body = body.take().unwrap_or_else(Body::empty);
+            let request = match self.upstream_request(&upstream, &parts, body, remote_addr, prefix)
+            {
+                Ok(request) => request,
+                Err(err_no) => return err_no_response(err_no),
+            };
+            let guard = ActiveGuard::new(upstream.clone());
+            let err = match self.client.request(request).await {
+                Ok(response) => break (response, guard),
+                Err(err) => err,
+            };
+            log::warn!("转发请求到{}失败: {}", upstream.base_url, err);
+            upstream.fail(self.max_failures, self.fail_timeout);
+            let next = if retry {
+                self.select().filter(|next| !Arc::ptr_eq(next, &upstream))
+            } else {
+                None
+            };
+            match next {
+                Some(next) => {
+                    retry = false;
+                    upstream = next;
+                }
+                None => return err_no_response(ErrNo::NoService(self.name.clone())),
+            }
+        };
+        upstream.succeed();
+        let (mut parts, body) = response.into_parts();
+        remove_hop_by_hop(&mut parts.headers);
+        let body = ProxiedBody {
+            inner: body,
+            _guard: guard,
+        };
+        return Ok(Response::from_parts(parts, BoxBody::new(body)));
+    }
+}
+
+/// The upstream response body, counting the request as in progress until it is dropped.
+#[pin_project]
+struct ProxiedBody {
+    #[pin]
+    inner: Incoming,
+    _guard: ActiveGuard,
+}
+
+impl hyper::body::Body for ProxiedBody {
+    type Data = Bytes;
+    type Error = anyhow::Error;
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        return self
+            .project()
+            .inner
+            .poll_frame(cx)
+            .map_err(anyhow::Error::from);
+    }
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_proxy() {
+    use crate::http::{read_body, Router, Server};
+    use hyper::Method;
+    use tokio::net::TcpListener;
+
+    struct Echo {
+        namespace: Vec<SharedString>,
+    }
+
+    #[async_trait]
+    impl HttpHandler for Echo {
+        fn namespace(&self) -> &[SharedString] {
+            &self.namespace
+        }
+        async fn handle(
+            &self,
+            request: Request<Body>,
+            _remote_addr: SocketAddr,
+            _request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let headers = request.headers();
+            let body = format!(
+                "{} {:?} {:?} {:?}",
+                request.uri(),
+                headers.get(X_FORWARDED_FOR),
+                headers.get(X_FORWARDED_HOST),
+                headers.get("x-hop"),
+            );
+            return Ok(Response::new(Body::from(body).into()));
+        }
+    }
+
+    async fn spawn(router: Router) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Server::new(router).serve(listener, std::future::pending()));
+        return addr;
+    }
+
+    let mut router = Router::new();
+    router
+        .register(Echo {
+            namespace: vec![SharedString::from_static("api")],
+        })
+        .unwrap();
+    let upstream = spawn(router).await;
+    // Nothing listens on a port just released.
+    let dead = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = ReverseProxy::new(
+        vec![SharedString::from_static("proxy")],
+        [
+            format!("http://{}/", dead),
+            format!("http://{}/api", upstream),
+        ],
+    )
+    .health_check(2, Duration::from_secs(60));
+    let mut router = Router::new();
+    router.register(proxy).unwrap();
+    let proxy = spawn(router).await;
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+    let send = |method: Method, path: &str, body: &'static str| {
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", proxy, path))
+            .header(CONNECTION, "x-hop")
+            .header("x-hop", "1")
+            .body(Body::from(body))
+            .unwrap();
+        let response = client.request(request);
+        async move {
+            let response = response.await.unwrap();
+            let status = response.status().as_u16();
+            let body = read_body(Body::from(response.into_body())).await.unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+    // A request with a body is not retried on the next upstream.
+    assert_eq!(503, send(Method::POST, "/proxy/a", "x").await.0);
+    // The dead upstream fails again, the request is retried on the next upstream, and
+    // the dead one is skipped after.
+    for _ in 0..4 {
+        let (status, body) = send(Method::GET, "/proxy/a/b?c=1", "").await;
+        assert_eq!(200, status);
+        let expected = format!("/api/a/b?c=1 Some(\"127.0.0.1\") Some(\"{}\") None", proxy);
+        assert_eq!(expected, body);
+    }
+}
+
+#[test]
+fn test_health_check() {
+    let proxy = ReverseProxy::new(
+        vec![SharedString::from_static("proxy")],
+        ["http://127.0.0.1:1"],
+    )
+    .health_check(1, Duration::from_secs(60));
+    assert!(proxy.select().is_some());
+    proxy.upstreams[0].fail(1, Duration::from_secs(60));
+    assert!(proxy.select().is_none());
+    // The state survives a panic while its lock is held.
+    let upstream = proxy.upstreams[0].clone();
+    std::thread::spawn(move || {
+        let _down_until = upstream.down_until.lock().unwrap();
+        panic!("poison the lock");
+    })
+    .join()
+    .unwrap_err();
+    assert!(proxy.select().is_none());
+    proxy.upstreams[0].succeed();
+    assert!(proxy.select().is_some());
+}
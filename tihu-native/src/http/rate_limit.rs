@@ -0,0 +1,618 @@
+use super::err_no_response;
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::{HeaderMap, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tihu::client_id::ClientId;
+use tihu::Handler;
+use tihu::Middleware;
+use tihu::SharedString;
+
+/// Keys tracked by [`MemoryRateLimitStore`] by default.
+pub const DEFAULT_MAX_KEYS: usize = 100_000;
+
+/// How requests are counted, both allow `limit` requests per `period` on average, `limit`
+/// must be positive.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    /// A bucket of `limit` tokens refilled continuously over `period`, each request takes a
+    /// token, so bursts of up to `limit` requests are allowed.
+    TokenBucket { limit: u32, period: Duration },
+    /// At most `limit` requests in any `period`, approximated by weighting the count of the
+    /// previous fixed window by its overlap with the sliding one.
+    SlidingWindow { limit: u32, period: Duration },
+}
+
+/// The counting state of a key, stores keep it between requests.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum LimiterState {
+    TokenBucket {
+        tokens: f64,
+        updated_at: Duration,
+    },
+    SlidingWindow {
+        window: u64,
+        current: u32,
+        previous: u32,
+    },
+}
+
+/// The outcome of counting a request.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time until the quota is fully available again.
+    pub reset: Duration,
+    /// Time until a request would be allowed, for denied requests.
+    pub retry_after: Option<Duration>,
+}
+
+fn secs(duration: Duration) -> u64 {
+    return duration.as_secs() + (0 < duration.subsec_nanos()) as u64;
+}
+
+impl Decision {
+    /// Write the `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset` and, for denied
+    /// requests, `Retry-After` headers, in seconds rounded up.
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(secs(self.reset)));
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from(secs(retry_after)));
+        }
+    }
+}
+
+impl Algorithm {
+    pub fn limit(&self) -> u32 {
+        match self {
+            Algorithm::TokenBucket { limit, .. } => *limit,
+            Algorithm::SlidingWindow { limit, .. } => *limit,
+        }
+    }
+
+    /// How long the state of a key matters after its last request, it may be dropped after.
+    pub fn state_ttl(&self) -> Duration {
+        match self {
+            Algorithm::TokenBucket { period, .. } => *period,
+            Algorithm::SlidingWindow { period, .. } => *period * 2,
+        }
+    }
+
+    /// Count a request at `now`, the time since the unix epoch, returning the new state.
+    /// A state of another algorithm is discarded.
+    pub fn check(&self, state: Option<LimiterState>, now: Duration) -> (LimiterState, Decision) {
+        match *self {
+            Algorithm::TokenBucket { limit, period } => {
+                let limit = limit.max(1);
+                let capacity = limit as f64;
+                let rate = capacity / period.as_secs_f64().max(f64::EPSILON);
+                let mut tokens = match state {
+                    Some(LimiterState::TokenBucket { tokens, updated_at }) => {
+                        let elapsed = now.saturating_sub(updated_at).as_secs_f64();
+                        (tokens + elapsed * rate).min(capacity)
+                    }
+                    _ => capacity,
+                };
+                let allowed = 1.0 <= tokens;
+                let retry_after = if allowed {
+                    tokens -= 1.0;
+                    None
+                } else {
+                    Some(Duration::from_secs_f64((1.0 - tokens) / rate))
+                };
+                let state = LimiterState::TokenBucket {
+                    tokens: tokens,
+                    updated_at: now,
+                };
+                let decision = Decision {
+                    allowed: allowed,
+                    limit: limit,
+                    remaining: tokens.floor() as u32,
+                    reset: Duration::from_secs_f64((capacity - tokens) / rate),
+                    retry_after: retry_after,
+                };
+                return (state, decision);
+            }
+            Algorithm::SlidingWindow { limit, period } => {
+                let limit = limit.max(1);
+                let period_nanos = period.as_nanos().max(1);
+                let window = (now.as_nanos() / period_nanos) as u64;
+                let window_start = Duration::from_nanos((window as u128 * period_nanos) as u64);
+                let (mut current, previous) = match state {
+                    Some(LimiterState::SlidingWindow {
+                        window: last_window,
+                        current,
+                        previous,
+                    }) => {
+                        if last_window == window {
+                            (current, previous)
+                        } else if last_window + 1 == window {
+                            (0, current)
+                        } else {
+                            (0, 0)
+                        }
+                    }
+                    _ => (0, 0),
+                };
+                let elapsed = (now - window_start).as_secs_f64() / period.as_secs_f64();
+                let weight = 1.0 - elapsed.min(1.0);
+                let count = previous as f64 * weight + current as f64;
+                let allowed = count + 1.0 <= limit as f64;
+                let retry_after = if allowed {
+                    current += 1;
+                    None
+                } else if current < limit {
+                    // Allowed once enough of the previous window slid out.
+                    let elapsed = 1.0 - (limit - current - 1) as f64 / previous as f64;
+                    Some((window_start + period.mul_f64(elapsed)).saturating_sub(now))
+                } else {
+                    // Allowed once enough of the current window slid out, in the next one.
+                    let elapsed = 1.0 - (limit - 1) as f64 / current as f64;
+                    Some((window_start + period + period.mul_f64(elapsed)).saturating_sub(now))
+                };
+                let count = previous as f64 * weight + current as f64;
+                let state = LimiterState::SlidingWindow {
+                    window: window,
+                    current: current,
+                    previous: previous,
+                };
+                let decision = Decision {
+                    allowed: allowed,
+                    limit: limit,
+                    remaining: (limit as f64 - count).max(0.0).floor() as u32,
+                    reset: window_start + period * 2 - now,
+                    retry_after: retry_after,
+                };
+                return (state, decision);
+            }
+        }
+    }
+}
+
+/// Storage of the [`LimiterState`]s, implement it on a shared database to rate limit across
+/// processes. Counting must be atomic per key.
+#[async_trait]
+pub trait RateLimitStore: Sync + Send + 'static {
+    async fn check(&self, key: &str, algorithm: &Algorithm) -> Result<Decision, anyhow::Error>;
+}
+
+/// A [`RateLimitStore`] keeping the states in memory.
+///
+/// Expired states are evicted as keys are checked, and when the number of keys reaches the
+/// maximum the states expiring first are evicted too. States are ordered by expiry, so
+/// eviction does not scan all the keys.
+pub struct MemoryRateLimitStore {
+    states: Mutex<MemoryStates>,
+    max_keys: usize,
+}
+
+#[derive(Default)]
+struct MemoryStates {
+    states: HashMap<String, (LimiterState, Instant)>,
+    expiries: BTreeSet<(Instant, String)>,
+}
+
+impl Default for MemoryRateLimitStore {
+    fn default() -> Self {
+        MemoryRateLimitStore {
+            states: Default::default(),
+            max_keys: DEFAULT_MAX_KEYS,
+        }
+    }
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> MemoryRateLimitStore {
+        Default::default()
+    }
+
+    pub fn max_keys(mut self, max_keys: usize) -> MemoryRateLimitStore {
+        self.max_keys = max_keys.max(1);
+        return self;
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn check(&self, key: &str, algorithm: &Algorithm) -> Result<Decision, anyhow::Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let instant = Instant::now();
+        let mut states = self.states.lock().unwrap_or_else(|err| err.into_inner());
+        let MemoryStates { states, expiries } = &mut *states;
+        let state = states
+            .get(key)
+            .filter(|(_, expires_at)| instant < *expires_at)
+            .map(|(state, _)| *state);
+        loop {
+            let full = state.is_none() && self.max_keys <= states.len();
+            let evict = expiries
+                .first()
+                .is_some_and(|(expires_at, _)| full || *expires_at <= instant);
+            if !evict {
+                break;
+            }
+            if let Some((_, first)) = expiries.pop_first() {
+                states.remove(&first);
+            }
+        }
+        let (state, decision) = algorithm.check(state, now);
+        let expires_at = instant + algorithm.state_ttl();
+        if let Some((_, previous)) = states.insert(key.to_string(), (state, expires_at)) {
+            expiries.remove(&(previous, key.to_string()));
+        }
+        expiries.insert((expires_at, key.to_string()));
+        return Ok(decision);
+    }
+}
+
+/// Counts requests per key with an [`Algorithm`] and a [`RateLimitStore`].
+pub struct RateLimiter {
+    algorithm: Algorithm,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// A rate limiter with a [`MemoryRateLimitStore`].
+    pub fn new(algorithm: Algorithm) -> RateLimiter {
+        return RateLimiter::with_store(algorithm, Arc::new(MemoryRateLimitStore::new()));
+    }
+
+    pub fn with_store<S>(algorithm: Algorithm, store: Arc<S>) -> RateLimiter
+    where
+        S: RateLimitStore,
+    {
+        RateLimiter {
+            algorithm: algorithm,
+            store: store,
+        }
+    }
+
+    pub async fn check(&self, key: &str) -> Result<Decision, anyhow::Error> {
+        return self.store.check(key, &self.algorithm).await;
+    }
+}
+
+/// Chooses the key requests are counted by, requests without a key are not limited.
+#[async_trait]
+pub trait KeyExtractor: Sync + Send + 'static {
+    async fn key(
+        &self,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Option<String>, anyhow::Error>;
+}
+
+/// Count requests by the IP address of the client.
+pub struct RemoteAddrKey;
+
+#[async_trait]
+impl KeyExtractor for RemoteAddrKey {
+    async fn key(
+        &self,
+        _request: &Request<Body>,
+        remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+    ) -> Result<Option<String>, anyhow::Error> {
+        return Ok(Some(remote_addr.ip().to_string()));
+    }
+}
+
+/// Count requests by the [`ClientId`] inserted into [`RequestData`] by an earlier
+/// authorizer, or by IP address for requests without one.
+pub struct ClientIdKey;
+
+#[async_trait]
+impl KeyExtractor for ClientIdKey {
+    async fn key(
+        &self,
+        _request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Option<String>, anyhow::Error> {
+        match request_data.get::<ClientId>() {
+            Some(client_id) => return Ok(Some(format!("client:{}", client_id.client_id()))),
+            None => return Ok(Some(remote_addr.ip().to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl<F> KeyExtractor for F
+where
+    F: Fn(&Request<Body>, SocketAddr, &RequestData) -> Option<String> + Sync + Send + 'static,
+{
+    async fn key(
+        &self,
+        request: &Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Option<String>, anyhow::Error> {
+        return Ok(self(request, remote_addr, request_data));
+    }
+}
+
+/// Rate limit the requests to the wrapped handler, responding [`ErrNo::TooFrequent`] to the
+/// requests over the limit, and adding `RateLimit-*` headers to the responses.
+pub struct RateLimit<H, K> {
+    handler: H,
+    limiter: Arc<RateLimiter>,
+    key: K,
+}
+
+impl<H, K> RateLimit<H, K>
+where
+    H: HttpHandler,
+    K: KeyExtractor,
+{
+    pub fn new(handler: H, limiter: Arc<RateLimiter>, key: K) -> RateLimit<H, K> {
+        RateLimit {
+            handler: handler,
+            limiter: limiter,
+            key: key,
+        }
+    }
+}
+
+#[async_trait]
+impl<H, K> HttpHandler for RateLimit<H, K>
+where
+    H: HttpHandler,
+    K: KeyExtractor,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let key = self.key.key(&request, remote_addr, request_data).await?;
+        let decision = match key {
+            Some(key) => Some(self.limiter.check(&key).await?),
+            None => None,
+        };
+        let mut response = match decision {
+            Some(decision) if !decision.allowed => err_no_response(ErrNo::TooFrequent)?,
+            _ => {
+                self.handler
+                    .handle(request, remote_addr, request_data, prefix)
+                    .await?
+            }
+        };
+        if let Some(decision) = decision {
+            decision.write_headers(response.headers_mut());
+        }
+        return Ok(response);
+    }
+}
+
+/// A [`Middleware`] rate limiting a [`tihu::Handler`] returning `Result<_, ErrNo>`, with the
+/// key taken from the handler input.
+pub struct RateLimitMiddleware<F> {
+    limiter: Arc<RateLimiter>,
+    key: Arc<F>,
+}
+
+impl<F> RateLimitMiddleware<F> {
+    pub fn new(limiter: Arc<RateLimiter>, key: F) -> RateLimitMiddleware<F> {
+        RateLimitMiddleware {
+            limiter: limiter,
+            key: Arc::new(key),
+        }
+    }
+}
+
+pub struct RateLimitedHandler<H, F> {
+    handler: H,
+    limiter: Arc<RateLimiter>,
+    key: Arc<F>,
+}
+
+impl<In, Out, H, F> Middleware<In, H> for RateLimitMiddleware<F>
+where
+    In: Send + 'static,
+    Out: Send + 'static,
+    H: Handler<In, Out = Result<Out, ErrNo>>,
+    F: Fn(&In) -> Option<String> + Sync + Send + 'static,
+{
+    type Output = RateLimitedHandler<H, F>;
+    fn transform(self, handler: H) -> Self::Output {
+        RateLimitedHandler {
+            handler: handler,
+            limiter: self.limiter,
+            key: self.key,
+        }
+    }
+}
+
+#[async_trait]
+impl<In, Out, H, F> Handler<In> for RateLimitedHandler<H, F>
+where
+    In: Send + 'static,
+    Out: Send + 'static,
+    H: Handler<In, Out = Result<Out, ErrNo>>,
+    F: Fn(&In) -> Option<String> + Sync + Send + 'static,
+{
+    type Out = Result<Out, ErrNo>;
+    async fn handle(&self, input: In) -> Self::Out {
+        if let Some(key) = (self.key)(&input) {
+            let decision = self.limiter.check(&key).await?;
+            if !decision.allowed {
+                return Err(ErrNo::TooFrequent);
+            }
+        }
+        return self.handler.handle(input).await;
+    }
+}
+
+#[test]
+fn test_algorithms() {
+    let period = Duration::from_secs(10);
+    let start = Duration::from_secs(1000);
+    let token_bucket = Algorithm::TokenBucket {
+        limit: 2,
+        period: period,
+    };
+    let (state, decision) = token_bucket.check(None, start);
+    assert!(decision.allowed);
+    assert_eq!(1, decision.remaining);
+    assert_eq!(Duration::from_secs(5), decision.reset);
+    let (state, decision) = token_bucket.check(Some(state), start);
+    assert!(decision.allowed);
+    assert_eq!(0, decision.remaining);
+    let (state, decision) = token_bucket.check(Some(state), start + Duration::from_secs(1));
+    assert!(!decision.allowed);
+    assert_eq!(Some(Duration::from_secs(4)), decision.retry_after);
+    let (_, decision) = token_bucket.check(Some(state), start + Duration::from_secs(5));
+    assert!(decision.allowed);
+
+    let sliding_window = Algorithm::SlidingWindow {
+        limit: 2,
+        period: period,
+    };
+    let (state, decision) = sliding_window.check(None, start);
+    assert!(decision.allowed);
+    let (state, decision) = sliding_window.check(Some(state), start + Duration::from_secs(5));
+    assert!(decision.allowed);
+    assert_eq!(0, decision.remaining);
+    let (state, decision) = sliding_window.check(Some(state), start + Duration::from_secs(9));
+    assert!(!decision.allowed);
+    assert_eq!(Some(Duration::from_secs(6)), decision.retry_after);
+    // Half of the previous window still counts, as one request.
+    let (state, decision) = sliding_window.check(Some(state), start + Duration::from_secs(15));
+    assert!(decision.allowed);
+    let (_, decision) = sliding_window.check(Some(state), start + Duration::from_secs(16));
+    assert!(!decision.allowed);
+    assert_eq!(Some(Duration::from_secs(4)), decision.retry_after);
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_memory_store() {
+    let algorithm = Algorithm::TokenBucket {
+        limit: 1,
+        period: Duration::from_secs(60),
+    };
+    let store = MemoryRateLimitStore::new().max_keys(2);
+    assert!(store.check("a", &algorithm).await.unwrap().allowed);
+    assert!(store.check("b", &algorithm).await.unwrap().allowed);
+    assert!(!store.check("a", &algorithm).await.unwrap().allowed);
+    // Full, "b" expires first and is evicted for "c".
+    assert!(store.check("c", &algorithm).await.unwrap().allowed);
+    assert!(store.check("b", &algorithm).await.unwrap().allowed);
+    let states = store.states.lock().unwrap();
+    assert_eq!(2, states.states.len());
+    assert_eq!(2, states.expiries.len());
+    assert!(!states.states.contains_key("a"));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_rate_limit() {
+    use super::TestRequest;
+    use hyper::StatusCode;
+
+    struct Hello;
+
+    #[async_trait]
+    impl HttpHandler for Hello {
+        fn namespace(&self) -> &[SharedString] {
+            &[]
+        }
+        async fn handle(
+            &self,
+            _request: Request<Body>,
+            _remote_addr: SocketAddr,
+            _request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            return Ok(Response::new(Body::from("hello").into()));
+        }
+    }
+
+    fn api_key(request: &Request<Body>, _: SocketAddr, _: &RequestData) -> Option<String> {
+        return request
+            .headers()
+            .get("x-api-key")
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string);
+    }
+
+    let limiter = Arc::new(RateLimiter::new(Algorithm::TokenBucket {
+        limit: 2,
+        period: Duration::from_secs(60),
+    }));
+    let handler = RateLimit::new(Hello, limiter.clone(), api_key);
+    for remaining in ["1", "0"] {
+        let response = TestRequest::get("/")
+            .header("x-api-key", "a")
+            .send(&handler)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status);
+        assert_eq!("hello", response.text().unwrap());
+        assert_eq!("2", response.headers["ratelimit-limit"]);
+        assert_eq!(remaining, response.headers["ratelimit-remaining"]);
+        assert!(!response.headers.contains_key(RETRY_AFTER));
+    }
+    let response = TestRequest::get("/")
+        .header("x-api-key", "a")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status);
+    assert_eq!("0", response.headers["ratelimit-remaining"]);
+    let retry_after = response.headers[RETRY_AFTER].to_str().unwrap();
+    assert!((1..=30).contains(&retry_after.parse::<u64>().unwrap()));
+    let reset = response.headers["ratelimit-reset"].to_str().unwrap();
+    assert!((1..=60).contains(&reset.parse::<u64>().unwrap()));
+    let response = response.api_response::<()>().unwrap();
+    assert_eq!(ErrNo::TooFrequent.code(), response.code);
+
+    // Other keys are counted apart, requests without a key are not limited.
+    let response = TestRequest::get("/")
+        .header("x-api-key", "b")
+        .send(&handler)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    for _ in 0..5 {
+        let response = TestRequest::get("/").send(&handler).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status);
+        assert!(!response.headers.contains_key("ratelimit-limit"));
+    }
+
+    let handler = RateLimitMiddleware::new(limiter, |key: &Option<String>| key.clone())
+        .transform(|_: Option<String>| async move { Ok(()) });
+    assert!(handler.handle(Some("c".to_string())).await.is_ok());
+    assert!(handler.handle(Some("c".to_string())).await.is_ok());
+    assert!(matches!(
+        handler.handle(Some("c".to_string())).await,
+        Err(ErrNo::TooFrequent)
+    ));
+    // The limiter is shared with the HTTP wrapper, which spent "a" already.
+    assert!(matches!(
+        handler.handle(Some("a".to_string())).await,
+        Err(ErrNo::TooFrequent)
+    ));
+    for _ in 0..5 {
+        assert!(handler.handle(None).await.is_ok());
+    }
+}
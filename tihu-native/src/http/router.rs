@@ -0,0 +1,327 @@
+use super::authorize::check_authorizers;
+use super::err_no_response;
+use super::extract::MatchedPrefix;
+use super::Body;
+use super::BoxBody;
+use super::HttpAuthorizer;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use hyper::{Request, Response};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tihu::SharedString;
+
+#[derive(Default)]
+struct Node {
+    handler: Option<Arc<dyn HttpHandler>>,
+    authorizers: Vec<Arc<dyn HttpAuthorizer>>,
+    children: HashMap<String, Node>,
+}
+
+/// Owns a set of [`HttpHandler`]s and dispatches each request to the handler whose
+/// `namespace()` is the longest match of the request path.
+///
+/// The matched part of the path is passed to the handler as `prefix`, and stored in
+/// [`RequestData`] as [`MatchedPrefix`] for [`super::PathParams`]. A router is itself an
+/// [`HttpHandler`], so routers can be nested by giving the inner one a namespace.
+///
+/// Authorizers attached to a namespace with [`Router::authorize`] guard every request whose
+/// path falls under that namespace, before the matched handler runs.
+#[derive(Default)]
+pub struct Router {
+    namespace: Vec<SharedString>,
+    root: Node,
+}
+
+/// Split namespace items into path segments, so both `["a", "b"]` and `["a/b"]` are accepted.
+pub(crate) fn namespace_segments(namespace: &[SharedString]) -> Vec<&str> {
+    return namespace
+        .iter()
+        .flat_map(|item| item.split('/'))
+        .filter(|segment| !segment.is_empty())
+        .collect();
+}
+
+/// Index of `path` where the part below `prefix` starts.
+pub(crate) fn prefix_len(path: &str, prefix: Option<&str>) -> usize {
+    match prefix {
+        Some(prefix) if path.starts_with(prefix) => prefix.len(),
+        _ => 0,
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Default::default()
+    }
+
+    /// Create a router that is mounted under `namespace` when registered in another router.
+    pub fn with_namespace(namespace: Vec<SharedString>) -> Router {
+        Router {
+            namespace: namespace,
+            root: Default::default(),
+        }
+    }
+
+    pub fn register<H>(&mut self, handler: H) -> Result<(), ErrNo>
+    where
+        H: HttpHandler,
+    {
+        return self.register_shared(Arc::new(handler));
+    }
+
+    pub fn register_shared(&mut self, handler: Arc<dyn HttpHandler>) -> Result<(), ErrNo> {
+        let mut node = &mut self.root;
+        for segment in namespace_segments(handler.namespace()) {
+            node = node.children.entry(segment.to_string()).or_default();
+        }
+        if node.handler.is_some() {
+            let namespace = namespace_segments(handler.namespace()).join("/");
+            return Err(ErrNo::ConfigError(
+                format!("命名空间\"/{}\"重复注册", namespace).into(),
+            ));
+        }
+        node.handler.replace(handler);
+        return Ok(());
+    }
+
+    /// Attach an authorizer to the namespace subtree, creating the namespace if needed.
+    pub fn authorize<A>(&mut self, namespace: &[SharedString], authorizer: A)
+    where
+        A: HttpAuthorizer,
+    {
+        let mut node = &mut self.root;
+        for segment in namespace_segments(namespace) {
+            node = node.children.entry(segment.to_string()).or_default();
+        }
+        node.authorizers.push(Arc::new(authorizer));
+    }
+
+    /// Find the handler for `path`, returning it together with the length of the matched
+    /// prefix of `path`. `start` is the index where the part of `path` handled by this router
+    /// begins.
+    pub fn route(&self, path: &str, start: usize) -> Option<(&Arc<dyn HttpHandler>, usize)> {
+        return self.resolve(path, start, |_| ());
+    }
+
+    fn resolve<'a, F>(
+        &'a self,
+        path: &str,
+        start: usize,
+        mut visit: F,
+    ) -> Option<(&'a Arc<dyn HttpHandler>, usize)>
+    where
+        F: FnMut(&'a Node),
+    {
+        let mut node = &self.root;
+        visit(node);
+        let mut matched = node.handler.as_ref().map(|handler| (handler, start));
+        let mut offset = start;
+        for segment in path[start..].split('/') {
+            let end = offset + segment.len();
+            offset = end + 1;
+            if segment.is_empty() {
+                continue;
+            }
+            match node.children.get(segment) {
+                Some(child) => {
+                    node = child;
+                    visit(node);
+                    if let Some(handler) = node.handler.as_ref() {
+                        matched.replace((handler, end));
+                    }
+                }
+                None => break,
+            }
+        }
+        return matched;
+    }
+}
+
+#[async_trait]
+impl HttpHandler for Router {
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let path = request.uri().path().to_string();
+        let start = prefix_len(&path, prefix);
+        let mut authorizers = Vec::new();
+        match self.resolve(&path, start, |node| authorizers.extend(&node.authorizers)) {
+            Some((handler, end)) => {
+                let prefix = if 0 == end { None } else { Some(&path[..end]) };
+                request_data.insert(MatchedPrefix(path[..end].to_string()));
+                if let Some(response) =
+                    check_authorizers(authorizers, &request, remote_addr, request_data, prefix)
+                        .await?
+                {
+                    return Ok(response);
+                }
+                return handler
+                    .handle(request, remote_addr, request_data, prefix)
+                    .await;
+            }
+            None => {
+                return err_no_response(ErrNo::NoSuchApi);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct NamedHandler(Vec<SharedString>);
+
+/// Responds its namespace, the prefix it is given and the tags of the authorizers that ran.
+#[cfg(test)]
+#[async_trait]
+impl HttpHandler for NamedHandler {
+    fn namespace(&self) -> &[SharedString] {
+        &self.0
+    }
+    async fn handle(
+        &self,
+        _request: Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let tags = request_data
+            .get::<Vec<&'static str>>()
+            .cloned()
+            .unwrap_or_default();
+        let body = format!(
+            "/{} {:?} {}",
+            namespace_segments(&self.0).join("/"),
+            prefix,
+            tags.join(",")
+        );
+        return Ok(Response::new(Body::from(body).into()));
+    }
+}
+
+/// Records its tag in [`RequestData`], denying the request if it is not `allow`.
+#[cfg(test)]
+struct TagAuthorizer(&'static str, bool);
+
+#[cfg(test)]
+#[async_trait]
+impl HttpAuthorizer for TagAuthorizer {
+    async fn authorize(
+        &self,
+        _request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        _prefix: Option<&str>,
+    ) -> Result<bool, anyhow::Error> {
+        let mut tags = request_data
+            .get::<Vec<&'static str>>()
+            .cloned()
+            .unwrap_or_default();
+        tags.push(self.0);
+        request_data.insert(tags);
+        return Ok(self.1);
+    }
+    fn rejection(&self) -> ErrNo {
+        return ErrNo::NotAllowed;
+    }
+}
+
+#[test]
+fn test_route() {
+    let mut router = Router::new();
+    for namespace in [vec![], vec!["api"], vec!["api", "user"], vec!["static/js"]] {
+        let namespace = namespace.into_iter().map(SharedString::from).collect();
+        router.register(NamedHandler(namespace)).unwrap();
+    }
+    router
+        .register(NamedHandler(vec![SharedString::from("api")]))
+        .expect_err("expected duplicate namespace");
+
+    let cases = [
+        ("/", 0, "", 0),
+        ("/index.html", 0, "", 0),
+        ("/api", 0, "/api", 4),
+        ("/api/", 0, "/api", 4),
+        ("/api/usr", 0, "/api", 4),
+        ("/api/user/1", 0, "/api/user", 9),
+        ("/api//user", 0, "/api/user", 10),
+        ("/static/js/app.js", 0, "/static/js", 10),
+        ("/v1/api/user", 3, "/api/user", 12),
+    ];
+    for (path, start, namespace, end) in cases {
+        let (handler, matched) = router.route(path, start).unwrap();
+        let expected: Vec<&str> = namespace.split('/').filter(|s| !s.is_empty()).collect();
+        assert_eq!(
+            namespace_segments(handler.namespace()),
+            expected,
+            "path: {}",
+            path
+        );
+        assert_eq!(matched, end, "path: {}", path);
+    }
+
+    let router = Router::new();
+    assert!(router.route("/api", 0).is_none());
+}
+
+#[tokio::test]
+async fn test_dispatch() {
+    use super::TestRequest;
+    use hyper::StatusCode;
+
+    let namespace = |items: &[&'static str]| -> Vec<SharedString> {
+        items
+            .iter()
+            .map(|item| SharedString::from_static(item))
+            .collect()
+    };
+    let mut router = Router::new();
+    router.register(NamedHandler(namespace(&["api"]))).unwrap();
+    router
+        .register(NamedHandler(namespace(&["api", "user"])))
+        .unwrap();
+    let mut inner = Router::with_namespace(namespace(&["v1"]));
+    inner.register(NamedHandler(namespace(&["order"]))).unwrap();
+    router.register(inner).unwrap();
+    router.authorize(&namespace(&["api"]), TagAuthorizer("api", true));
+    router.authorize(&namespace(&["api", "user"]), TagAuthorizer("user", true));
+    router.authorize(&namespace(&["api", "admin"]), TagAuthorizer("admin", false));
+
+    let cases = [
+        ("/api", "/api Some(\"/api\") api"),
+        ("/api/order/1", "/api Some(\"/api\") api"),
+        ("/api/user/1", "/api/user Some(\"/api/user\") api,user"),
+        ("/v1/order/1", "/order Some(\"/v1/order\") "),
+    ];
+    for (path, expected) in cases {
+        let response = TestRequest::get(path).send(&router).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status, "path: {}", path);
+        assert_eq!(expected, response.text().unwrap(), "path: {}", path);
+    }
+
+    // An authorizer of a namespace without handler still guards it.
+    let response = TestRequest::get("/api/admin/1")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::FORBIDDEN, response.status);
+    let response = response.api_response::<()>().unwrap();
+    assert_eq!(ErrNo::NotAllowed.code(), response.code);
+
+    for path in ["/", "/user", "/v1/user"] {
+        let response = TestRequest::get(path).send(&router).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status, "path: {}", path);
+        let response = response.api_response::<()>().unwrap();
+        assert_eq!(ErrNo::NoSuchApi.code(), response.code);
+        assert_eq!(ErrNo::NoSuchApi.to_string(), response.message.as_ref());
+    }
+}
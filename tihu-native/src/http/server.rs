@@ -0,0 +1,360 @@
+use super::err_no_response;
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tihu::SharedString;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+
+/// Time given to open connections to complete after shutdown by default.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time given to clients to send the request headers by default.
+pub const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serve a root [`HttpHandler`] over HTTP/1.1 and HTTP/2, with a fresh [`RequestData`] for
+/// each request.
+///
+/// Errors returned by the handler are logged, and responded as a JSON error with the
+/// status of the [`ErrNo`] when they are one, as an internal error otherwise.
+pub struct Server {
+    handler: Arc<dyn HttpHandler>,
+    keep_alive: bool,
+    header_read_timeout: Option<Duration>,
+    http2_keep_alive_interval: Option<Duration>,
+    drain_timeout: Duration,
+}
+
+impl Server {
+    pub fn new<H>(handler: H) -> Server
+    where
+        H: HttpHandler,
+    {
+        Server {
+            handler: Arc::new(handler),
+            keep_alive: true,
+            header_read_timeout: Some(DEFAULT_HEADER_READ_TIMEOUT),
+            http2_keep_alive_interval: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+
+    /// Enable HTTP/1.1 keep-alive, enabled by default.
+    pub fn keep_alive(mut self, keep_alive: bool) -> Server {
+        self.keep_alive = keep_alive;
+        return self;
+    }
+
+    /// Close HTTP/1.1 connections not sending the request headers in time, `None` waits
+    /// forever.
+    pub fn header_read_timeout(mut self, timeout: Option<Duration>) -> Server {
+        self.header_read_timeout = timeout;
+        return self;
+    }
+
+    /// Send HTTP/2 pings at this interval, to detect dead connections.
+    pub fn http2_keep_alive_interval(mut self, interval: Option<Duration>) -> Server {
+        self.http2_keep_alive_interval = interval;
+        return self;
+    }
+
+    /// Set how long open connections are given to complete after shutdown, they are closed
+    /// after that.
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Server {
+        self.drain_timeout = drain_timeout;
+        return self;
+    }
+
+    fn builder(&self) -> Builder<TokioExecutor> {
+        let mut builder = Builder::new(TokioExecutor::new());
+        builder
+            .http1()
+            .timer(TokioTimer::new())
+            .keep_alive(self.keep_alive)
+            .header_read_timeout(self.header_read_timeout);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.http2_keep_alive_interval);
+        return builder;
+    }
+
+    /// Serve the connections of `listener` until `shutdown` completes, then stop accepting
+    /// and wait for the open connections to complete, at most the drain timeout after which
+    /// they are closed.
+    pub async fn serve<F>(self, listener: TcpListener, shutdown: F) -> Result<(), anyhow::Error>
+    where
+        F: Future<Output = ()>,
+    {
+        return self
+            .serve_with(
+                || async {
+                    let (stream, remote_addr) = listener.accept().await?;
+                    return Ok((stream, remote_addr));
+                },
+                shutdown,
+            )
+            .await;
+    }
+
+    /// Like [`Server::serve`], over a Unix domain socket. Unix peers have no socket address,
+    /// handlers see `127.0.0.1:0` as the remote address.
+    #[cfg(unix)]
+    pub async fn serve_unix<F>(
+        self,
+        listener: UnixListener,
+        shutdown: F,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: Future<Output = ()>,
+    {
+        return self
+            .serve_with(
+                || async {
+                    let (stream, _) = listener.accept().await?;
+                    return Ok((stream, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))));
+                },
+                shutdown,
+            )
+            .await;
+    }
+
+    async fn serve_with<A, Fut, S, F>(self, accept: A, shutdown: F) -> Result<(), anyhow::Error>
+    where
+        A: Fn() -> Fut,
+        Fut: Future<Output = io::Result<(S, SocketAddr)>>,
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Future<Output = ()>,
+    {
+        let builder = self.builder();
+        let graceful = GracefulShutdown::new();
+        let mut connections = JoinSet::new();
+        tokio::pin!(shutdown);
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        // Running out of file descriptors for example, retry a bit later.
+                        log::error!("接受连接失败: {}", err);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                _ = &mut shutdown => break,
+            };
+            let handler = self.handler.clone();
+            let service = service_fn(move |request: Request<Incoming>| {
+                let handler = handler.clone();
+                async move {
+                    let request = request.map(Body::from);
+                    let response = handle(handler.as_ref(), request, remote_addr).await;
+                    return Ok::<_, Infallible>(response);
+                }
+            });
+            let connection = builder
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let connection = graceful.watch(connection);
+            connections.spawn(async move {
+                if let Err(err) = connection.await {
+                    log::debug!("连接{}出错: {}", remote_addr, err);
+                }
+            });
+        }
+        if tokio::time::timeout(self.drain_timeout, graceful.shutdown())
+            .await
+            .is_err()
+        {
+            log::warn!("等待连接关闭超时");
+            connections.shutdown().await;
+        }
+        return Ok(());
+    }
+}
+
+async fn handle(
+    handler: &dyn HttpHandler,
+    request: Request<Body>,
+    remote_addr: SocketAddr,
+) -> Response<BoxBody> {
+    let mut request_data = RequestData::new();
+    let err = match handler
+        .handle(request, remote_addr, &mut request_data, None)
+        .await
+    {
+        Ok(response) => return response,
+        Err(err) => err,
+    };
+    log::error!("处理请求失败: {:?}", err);
+    let err_no = match err.downcast::<ErrNo>() {
+        Ok(err_no) => err_no,
+        // Unexpected errors may reveal internals, they are not responded as is.
+        Err(_) => ErrNo::CommonError(SharedString::from_static("服务器内部错误")),
+    };
+    return err_no_response(err_no).unwrap_or_else(|_| {
+        let mut response = Response::new(Body::empty().into());
+        *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+        response
+    });
+}
+
+/// Greets the client, failing on `/hello/fail` and never responding on `/hello/slow`.
+#[cfg(test)]
+struct Hello {
+    namespace: Vec<SharedString>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl HttpHandler for Hello {
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+        _prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        if "/hello/fail" == request.uri().path() {
+            return Err(ErrNo::NotAllowed.into());
+        }
+        if "/hello/slow" == request.uri().path() {
+            std::future::pending::<()>().await;
+        }
+        let body = format!("hello {}", remote_addr.ip());
+        return Ok(Response::new(Body::from(body).into()));
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_serve() {
+    use super::{read_body, Router};
+    use hyper_util::client::legacy::Client;
+
+    let mut router = Router::new();
+    router
+        .register(Hello {
+            namespace: vec![SharedString::from_static("hello")],
+        })
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(Server::new(router).serve(listener, async move {
+        shutdown_signal.await.ok();
+    }));
+
+    let client = Client::builder(TokioExecutor::new()).build_http::<Body>();
+    let response = client
+        .get(format!("http://{}/hello", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let body = read_body(Body::from(response.into_body())).await.unwrap();
+    assert_eq!(b"hello 127.0.0.1", &body[..]);
+    let response = client
+        .get(format!("http://{}/hello/fail", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(403, response.status().as_u16());
+
+    shutdown.send(()).unwrap();
+    drop(client);
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_drain_timeout() {
+    use super::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut router = Router::new();
+    router
+        .register(Hello {
+            namespace: vec![SharedString::from_static("hello")],
+        })
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+    let server = Server::new(router).drain_timeout(Duration::from_millis(50));
+    let server = tokio::spawn(server.serve(listener, async move {
+        shutdown_signal.await.ok();
+    }));
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /hello/slow HTTP/1.1\r\nhost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    let mut buf = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buf))
+        .await
+        .expect("连接没有被关闭");
+    assert!(read.map(|len| 0 == len).unwrap_or(true));
+}
+
+#[cfg(all(test, unix))]
+#[tokio::test]
+async fn test_serve_unix() {
+    use super::Router;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut router = Router::new();
+    router
+        .register(Hello {
+            namespace: vec![SharedString::from_static("hello")],
+        })
+        .unwrap();
+    let path = std::env::temp_dir().join(format!("tihu-server-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let (shutdown, shutdown_signal) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(Server::new(router).serve_unix(listener, async move {
+        shutdown_signal.await.ok();
+    }));
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET /hello HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    // Unix peers are seen as the loopback address.
+    assert!(
+        response.ends_with("\r\n\r\nhello 127.0.0.1"),
+        "{}",
+        response
+    );
+
+    shutdown.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,616 @@
+use super::hmac_sha256;
+use super::Body;
+use super::BoxBody;
+use super::FromRequest;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use headers::{Cookie, HeaderMapExt};
+use hmac::Mac;
+use hyper::header::{HeaderValue, SET_COOKIE};
+use hyper::{Request, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use tihu::SharedString;
+
+/// Name of the session cookie by default.
+pub const DEFAULT_COOKIE_NAME: &str = "session";
+/// Sessions not accessed for this long expire by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Sessions expire this long after they were created by default, even when in use.
+pub const DEFAULT_ABSOLUTE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Expired sessions are swept from [`MemoryStore`] every this many writes.
+const SWEEP_INTERVAL: usize = 1024;
+
+/// The stored state of a session.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionRecord {
+    pub data: HashMap<String, serde_json::Value>,
+    pub created_at: SystemTime,
+    pub accessed_at: SystemTime,
+}
+
+/// Storage of session records, keyed by session id.
+#[async_trait]
+pub trait SessionStore: Sync + Send + 'static {
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord>, anyhow::Error>;
+    /// Store the record, which may be discarded once `ttl` elapsed.
+    async fn store(
+        &self,
+        id: &str,
+        record: &SessionRecord,
+        ttl: Duration,
+    ) -> Result<(), anyhow::Error>;
+    async fn remove(&self, id: &str) -> Result<(), anyhow::Error>;
+}
+
+/// A [`SessionStore`] keeping the sessions in memory, they are lost on restart and not
+/// shared between processes.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<MemorySessions>,
+}
+
+#[derive(Default)]
+struct MemorySessions {
+    records: HashMap<String, (SessionRecord, SystemTime)>,
+    writes: usize,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        Default::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord>, anyhow::Error> {
+        let sessions = &mut lock(&self.sessions).records;
+        match sessions.get(id) {
+            Some((record, expires_at)) if SystemTime::now() < *expires_at => {
+                return Ok(Some(record.clone()));
+            }
+            Some(_) => {
+                sessions.remove(id);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        }
+    }
+    async fn store(
+        &self,
+        id: &str,
+        record: &SessionRecord,
+        ttl: Duration,
+    ) -> Result<(), anyhow::Error> {
+        let now = SystemTime::now();
+        let mut sessions = lock(&self.sessions);
+        sessions.writes += 1;
+        if sessions.writes.is_multiple_of(SWEEP_INTERVAL) {
+            sessions
+                .records
+                .retain(|_, (_, expires_at)| now < *expires_at);
+        }
+        sessions
+            .records
+            .insert(id.to_string(), (record.clone(), now + ttl));
+        return Ok(());
+    }
+    async fn remove(&self, id: &str) -> Result<(), anyhow::Error> {
+        lock(&self.sessions).records.remove(id);
+        return Ok(());
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return mutex.lock().unwrap_or_else(|err| err.into_inner());
+}
+
+#[derive(Default, Debug)]
+struct SessionState {
+    id: Option<String>,
+    record: Option<SessionRecord>,
+    renewed: bool,
+    destroyed: bool,
+    /// The request carried a cookie of a session not valid anymore, it is expired unless a new
+    /// session is stored.
+    stale_cookie: bool,
+}
+
+/// The session of the current request, available to handlers wrapped in [`Sessions`].
+///
+/// A session is only stored, and its cookie set, once data is inserted into it. Clones share
+/// the same state.
+#[derive(Clone, Default, Debug)]
+pub struct Session {
+    state: Arc<Mutex<SessionState>>,
+}
+
+impl Session {
+    fn new(id: Option<String>, record: Option<SessionRecord>) -> Session {
+        Session {
+            state: Arc::new(Mutex::new(SessionState {
+                id: id,
+                record: record,
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn with_stale_cookie() -> Session {
+        Session {
+            state: Arc::new(Mutex::new(SessionState {
+                stale_cookie: true,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn get<T>(&self, key: &str) -> Option<T>
+    where
+        T: DeserializeOwned,
+    {
+        let state = lock(&self.state);
+        let value = state.record.as_ref()?.data.get(key)?;
+        return T::deserialize(value).ok();
+    }
+
+    /// Like [`Session::get`], but returns [`ErrNo::LoginRequired`] when the value is missing,
+    /// for values set on login.
+    pub fn get_required<T>(&self, key: &str) -> Result<T, ErrNo>
+    where
+        T: DeserializeOwned,
+    {
+        return self.get(key).ok_or(ErrNo::LoginRequired);
+    }
+
+    pub fn insert<T>(&self, key: &str, value: &T) -> Result<(), ErrNo>
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = serde_json::to_value(value).map_err(ErrNo::SerializeError)?;
+        let mut state = lock(&self.state);
+        let now = SystemTime::now();
+        let record = state.record.get_or_insert_with(|| SessionRecord {
+            data: HashMap::new(),
+            created_at: now,
+            accessed_at: now,
+        });
+        record.data.insert(key.to_string(), value);
+        state.destroyed = false;
+        return Ok(());
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut state = lock(&self.state);
+        if let Some(record) = state.record.as_mut() {
+            record.data.remove(key);
+        }
+    }
+
+    /// Give the session a new id, keeping its data. Call it when the privilege level changes,
+    /// on login especially, so a session id planted before cannot be used.
+    pub fn renew(&self) {
+        lock(&self.state).renewed = true;
+    }
+
+    /// Remove the session data from the store and expire the cookie, on logout typically.
+    pub fn destroy(&self) {
+        let mut state = lock(&self.state);
+        state.record = None;
+        state.renewed = true;
+        state.destroyed = true;
+    }
+
+    /// The session id, `None` for a session not stored yet.
+    pub fn id(&self) -> Option<String> {
+        return lock(&self.state).id.clone();
+    }
+}
+
+#[async_trait]
+impl FromRequest for Session {
+    async fn try_extract(
+        _request: &Request<Body>,
+        _remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        let session = request_data.get::<Session>().cloned().ok_or_else(|| {
+            ErrNo::ConfigError(SharedString::from_static("请求没有经过会话处理器"))
+        })?;
+        return Ok(session);
+    }
+}
+
+/// Attributes of the session cookie.
+#[derive(Clone, Debug)]
+pub struct CookieOptions {
+    pub name: SharedString,
+    pub path: SharedString,
+    pub domain: Option<SharedString>,
+    pub secure: bool,
+    pub http_only: bool,
+    /// The `SameSite` attribute, `Strict`, `Lax` or `None`.
+    pub same_site: Option<SharedString>,
+}
+
+impl Default for CookieOptions {
+    fn default() -> Self {
+        CookieOptions {
+            name: SharedString::from_static(DEFAULT_COOKIE_NAME),
+            path: SharedString::from_static("/"),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: Some(SharedString::from_static("Lax")),
+        }
+    }
+}
+
+impl CookieOptions {
+    /// Format a `Set-Cookie` value, with `Max-Age` when given.
+    pub fn set_cookie(&self, value: &str, max_age: Option<Duration>) -> String {
+        let mut cookie = format!("{}={}; Path={}", self.name, value, self.path);
+        if let Some(domain) = self.domain.as_ref() {
+            cookie.push_str("; Domain=");
+            cookie.push_str(domain);
+        }
+        if let Some(max_age) = max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age.as_secs()));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = self.same_site.as_ref() {
+            cookie.push_str("; SameSite=");
+            cookie.push_str(same_site);
+        }
+        return cookie;
+    }
+}
+
+/// Append a `Set-Cookie` header to `response`.
+pub fn append_set_cookie(
+    response: &mut Response<BoxBody>,
+    set_cookie: &str,
+) -> Result<(), anyhow::Error> {
+    response
+        .headers_mut()
+        .append(SET_COOKIE, HeaderValue::from_str(set_cookie)?);
+    return Ok(());
+}
+
+/// Load the [`Session`] of the requests to the wrapped handler, and store it after the handler
+/// responded.
+///
+/// The cookie holds the session id signed with HMAC-SHA256, cookies with an invalid signature
+/// or of a session not found are ignored and expired. Sessions expire after the idle timeout without requests, and after the absolute
+/// timeout since they were created.
+pub struct Sessions<H> {
+    handler: H,
+    store: Arc<dyn SessionStore>,
+    key: Vec<u8>,
+    cookie: CookieOptions,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+}
+
+impl<H> Sessions<H>
+where
+    H: HttpHandler,
+{
+    /// `key` signs the session ids, it should be random and at least 32 bytes long.
+    pub fn new<S>(handler: H, store: Arc<S>, key: &[u8]) -> Sessions<H>
+    where
+        S: SessionStore,
+    {
+        Sessions {
+            handler: handler,
+            store: store,
+            key: key.to_vec(),
+            cookie: Default::default(),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            absolute_timeout: DEFAULT_ABSOLUTE_TIMEOUT,
+        }
+    }
+
+    pub fn cookie(mut self, cookie: CookieOptions) -> Sessions<H> {
+        self.cookie = cookie;
+        return self;
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Sessions<H> {
+        self.idle_timeout = idle_timeout;
+        return self;
+    }
+
+    pub fn absolute_timeout(mut self, absolute_timeout: Duration) -> Sessions<H> {
+        self.absolute_timeout = absolute_timeout;
+        return self;
+    }
+
+    fn sign(&self, id: &str) -> String {
+        let mut mac = hmac_sha256(&self.key);
+        mac.update(id.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        return format!("{}.{}", id, signature);
+    }
+
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, signature) = value.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let mut mac = hmac_sha256(&self.key);
+        mac.update(id.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+        return Some(id);
+    }
+
+    async fn load(&self, request: &Request<Body>) -> Result<Session, anyhow::Error> {
+        let value = request
+            .headers()
+            .typed_get::<Cookie>()
+            .and_then(|cookie| cookie.get(&self.cookie.name).map(str::to_string));
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(Session::default()),
+        };
+        let id = match self.verify(&value) {
+            Some(id) => id.to_string(),
+            None => return Ok(Session::with_stale_cookie()),
+        };
+        let record = match self.store.load(&id).await? {
+            Some(record) => record,
+            None => return Ok(Session::with_stale_cookie()),
+        };
+        let now = SystemTime::now();
+        let expired = record.created_at + self.absolute_timeout <= now
+            || record.accessed_at + self.idle_timeout <= now;
+        if expired {
+            self.store.remove(&id).await?;
+            return Ok(Session::with_stale_cookie());
+        }
+        return Ok(Session::new(Some(id), Some(record)));
+    }
+
+    /// Store or remove the session, returning the `Set-Cookie` value to respond.
+    async fn save(&self, session: &Session) -> Result<Option<String>, anyhow::Error> {
+        let (old_id, record, renewed, destroyed, stale_cookie) = {
+            let state = lock(&session.state);
+            (
+                state.id.clone(),
+                state.record.clone(),
+                state.renewed,
+                state.destroyed,
+                state.stale_cookie,
+            )
+        };
+        let mut record = match record {
+            Some(record) if !destroyed => record,
+            _ => {
+                if let Some(old_id) = old_id {
+                    self.store.remove(&old_id).await?;
+                    return Ok(Some(self.cookie.set_cookie("", Some(Duration::ZERO))));
+                }
+                // Expire the cookie, so browsers stop sending it.
+                if stale_cookie {
+                    return Ok(Some(self.cookie.set_cookie("", Some(Duration::ZERO))));
+                }
+                return Ok(None);
+            }
+        };
+        let now = SystemTime::now();
+        record.accessed_at = now;
+        let remaining = (record.created_at + self.absolute_timeout)
+            .duration_since(now)
+            .unwrap_or_default();
+        let ttl = remaining.min(self.idle_timeout);
+        let id = match old_id.as_ref() {
+            Some(old_id) if !renewed => old_id.clone(),
+            _ => {
+                if let Some(old_id) = old_id.as_ref() {
+                    self.store.remove(old_id).await?;
+                }
+                new_session_id()?
+            }
+        };
+        self.store.store(&id, &record, ttl).await?;
+        lock(&session.state).id.replace(id.clone());
+        // Unchanged sessions are stored and their cookie refreshed too, to extend the idle
+        // expiry.
+        return Ok(Some(self.cookie.set_cookie(&self.sign(&id), Some(ttl))));
+    }
+}
+
+fn new_session_id() -> Result<String, anyhow::Error> {
+    let mut bytes = [0u8; 32];
+    getrandom::getrandom(&mut bytes).map_err(|err| anyhow::anyhow!(err))?;
+    return Ok(URL_SAFE_NO_PAD.encode(bytes));
+}
+
+#[async_trait]
+impl<H> HttpHandler for Sessions<H>
+where
+    H: HttpHandler,
+{
+    fn namespace(&self) -> &[SharedString] {
+        self.handler.namespace()
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let session = self.load(&request).await?;
+        request_data.insert(session.clone());
+        let mut response = self
+            .handler
+            .handle(request, remote_addr, request_data, prefix)
+            .await?;
+        if let Some(set_cookie) = self.save(&session).await? {
+            append_set_cookie(&mut response, &set_cookie)?;
+        }
+        return Ok(response);
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_session_lifecycle() {
+    let store = Arc::new(MemoryStore::new());
+    let sessions = Sessions::new(super::Router::new(), store.clone(), b"secret");
+    let signed = sessions.sign("abc");
+    assert_eq!(Some("abc"), sessions.verify(&signed));
+    assert_eq!(None, sessions.verify(&signed.replacen("abc", "abd", 1)));
+    assert_eq!(None, sessions.verify("abc"));
+
+    let session = Session::default();
+    assert_eq!(None, sessions.save(&session).await.unwrap());
+    session.insert("user_id", &1).unwrap();
+    let set_cookie = sessions.save(&session).await.unwrap().unwrap();
+    let id = session.id().unwrap();
+    assert!(set_cookie.starts_with(&format!(
+        "session={}; Path=/; Max-Age=1800",
+        sessions.sign(&id)
+    )));
+    let record = store.load(&id).await.unwrap().unwrap();
+    assert_eq!(Some(&serde_json::json!(1)), record.data.get("user_id"));
+
+    let session = Session::new(Some(id.clone()), Some(record));
+    session.renew();
+    sessions.save(&session).await.unwrap();
+    let renewed_id = session.id().unwrap();
+    assert_ne!(id, renewed_id);
+    assert!(store.load(&id).await.unwrap().is_none());
+    assert_eq!(Some(1), session.get::<i32>("user_id"));
+
+    session.destroy();
+    let set_cookie = sessions.save(&session).await.unwrap().unwrap();
+    assert!(set_cookie.starts_with("session=; Path=/; Max-Age=0"));
+    assert!(store.load(&renewed_id).await.unwrap().is_none());
+    assert!(matches!(
+        session.get_required::<i32>("user_id"),
+        Err(ErrNo::LoginRequired)
+    ));
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_sessions_handler() {
+    use super::TestRequest;
+    use hyper::header::COOKIE;
+
+    /// Responds the user id of the session, and sets it on `/login`.
+    struct User;
+
+    #[async_trait]
+    impl HttpHandler for User {
+        fn namespace(&self) -> &[SharedString] {
+            &[]
+        }
+        async fn handle(
+            &self,
+            request: Request<Body>,
+            remote_addr: SocketAddr,
+            request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let session = request_data
+                .try_get::<Session>(&request, remote_addr)
+                .await?
+                .clone();
+            if "/login" == request.uri().path() {
+                session.insert("user_id", &1)?;
+            }
+            let user_id = session.get::<i32>("user_id");
+            return Ok(Response::new(Body::from(format!("{:?}", user_id)).into()));
+        }
+    }
+
+    let store = Arc::new(MemoryStore::new());
+    let sessions = Sessions::new(User, store.clone(), b"secret");
+    let send = |path: &'static str, cookie: Option<String>| {
+        let sessions = &sessions;
+        async move {
+            let mut request = TestRequest::get(path);
+            if let Some(cookie) = cookie {
+                request = request.header(COOKIE, format!("other=1; session={}", cookie));
+            }
+            let response = request.send(sessions).await.unwrap();
+            let set_cookie = response
+                .headers
+                .get(SET_COOKIE)
+                .map(|value| value.to_str().unwrap().to_string());
+            return (response.text().unwrap().to_string(), set_cookie);
+        }
+    };
+    let cookie_id = |set_cookie: &str| -> String {
+        let value = set_cookie
+            .strip_prefix("session=")
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap();
+        return sessions.verify(value).unwrap().to_string();
+    };
+
+    assert_eq!(("None".to_string(), None), send("/", None).await);
+    let (text, set_cookie) = send("/login", None).await;
+    assert_eq!("Some(1)", text);
+    let id = cookie_id(&set_cookie.unwrap());
+    let (text, set_cookie) = send("/", Some(sessions.sign(&id))).await;
+    assert_eq!("Some(1)", text);
+    assert_eq!(id, cookie_id(&set_cookie.unwrap()));
+
+    // Dead cookies are expired, unless a new session replaces them.
+    let expired = "session=; Path=/; Max-Age=0; Secure; HttpOnly; SameSite=Lax".to_string();
+    for cookie in [
+        sessions.sign("forged").replacen("forged", &id, 1),
+        format!("{}.", id),
+        sessions.sign("missing"),
+    ] {
+        assert_eq!(
+            ("None".to_string(), Some(expired.clone())),
+            send("/", Some(cookie)).await
+        );
+    }
+
+    let now = SystemTime::now();
+    let day = Duration::from_secs(24 * 60 * 60);
+    let idle = SessionRecord {
+        data: HashMap::from([("user_id".to_string(), serde_json::json!(1))]),
+        created_at: now,
+        accessed_at: now - DEFAULT_IDLE_TIMEOUT,
+    };
+    let absolute = SessionRecord {
+        created_at: now - DEFAULT_ABSOLUTE_TIMEOUT,
+        accessed_at: now,
+        ..idle.clone()
+    };
+    for record in [idle, absolute] {
+        store.store("old", &record, day).await.unwrap();
+        assert_eq!(
+            ("None".to_string(), Some(expired.clone())),
+            send("/", Some(sessions.sign("old"))).await
+        );
+        assert!(store.load("old").await.unwrap().is_none());
+        store.store("old", &record, day).await.unwrap();
+        let (text, set_cookie) = send("/login", Some(sessions.sign("old"))).await;
+        assert_eq!("Some(1)", text);
+        assert_ne!("old", cookie_id(&set_cookie.unwrap()));
+        assert!(store.load("old").await.unwrap().is_none());
+    }
+}
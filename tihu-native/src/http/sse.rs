@@ -0,0 +1,281 @@
+use super::Body;
+use super::BoxBody;
+use crate::ErrNo;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::Stream;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Response, StatusCode};
+use pin_project::pin_project;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Interval of the keep-alive comments sent by [`SseBody`] by default.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A server-sent event, see the `text/event-stream` format.
+#[derive(Clone, Default, Debug)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    pub fn new() -> Event {
+        Default::default()
+    }
+
+    /// Set the data, which may span multiple lines.
+    pub fn data<D>(mut self, data: D) -> Event
+    where
+        D: Into<String>,
+    {
+        self.data.replace(data.into());
+        return self;
+    }
+
+    /// Set the data to `data` serialized as JSON.
+    pub fn json_data<T>(self, data: &T) -> Result<Event, ErrNo>
+    where
+        T: Serialize + ?Sized,
+    {
+        let data = serde_json::to_string(data).map_err(ErrNo::SerializeError)?;
+        return Ok(self.data(data));
+    }
+
+    /// Set the event id, line breaks are removed since they cannot be represented.
+    pub fn id<I>(mut self, id: I) -> Event
+    where
+        I: Into<String>,
+    {
+        self.id.replace(id.into());
+        return self;
+    }
+
+    /// Set the event type, line breaks are removed since they cannot be represented.
+    pub fn event<E>(mut self, event: E) -> Event
+    where
+        E: Into<String>,
+    {
+        self.event.replace(event.into());
+        return self;
+    }
+
+    /// Set the reconnection time of the client.
+    pub fn retry(mut self, retry: Duration) -> Event {
+        self.retry.replace(retry);
+        return self;
+    }
+
+    /// Set a comment, which is ignored by clients.
+    pub fn comment<C>(mut self, comment: C) -> Event
+    where
+        C: Into<String>,
+    {
+        self.comment.replace(comment.into());
+        return self;
+    }
+
+    pub fn encode(&self, buffer: &mut BytesMut) {
+        fn put_lines(buffer: &mut BytesMut, field: &str, value: &str) {
+            for line in value
+                .split("\r\n")
+                .flat_map(|line| line.split(['\r', '\n']))
+            {
+                buffer.put_slice(field.as_bytes());
+                buffer.put_slice(line.as_bytes());
+                buffer.put_u8(b'\n');
+            }
+        }
+        fn put_line(buffer: &mut BytesMut, field: &str, value: &str) {
+            buffer.put_slice(field.as_bytes());
+            for chunk in value.split(['\r', '\n']) {
+                buffer.put_slice(chunk.as_bytes());
+            }
+            buffer.put_u8(b'\n');
+        }
+        if let Some(comment) = self.comment.as_ref() {
+            put_lines(buffer, ":", comment);
+        }
+        if let Some(id) = self.id.as_ref() {
+            put_line(buffer, "id: ", id);
+        }
+        if let Some(event) = self.event.as_ref() {
+            put_line(buffer, "event: ", event);
+        }
+        if let Some(retry) = self.retry.as_ref() {
+            put_line(buffer, "retry: ", &retry.as_millis().to_string());
+        }
+        if let Some(data) = self.data.as_ref() {
+            put_lines(buffer, "data: ", data);
+        }
+        buffer.put_u8(b'\n');
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+        self.encode(&mut buffer);
+        return buffer.freeze();
+    }
+}
+
+/// A `text/event-stream` body, made from a stream of [`Event`]s.
+///
+/// When no event is sent for the keep-alive interval, a comment is sent instead, so proxies
+/// do not close the idle connection.
+pub struct SseBody<S> {
+    stream: S,
+    keep_alive: Option<Duration>,
+}
+
+impl<S> SseBody<S>
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    pub fn new(stream: S) -> SseBody<S> {
+        SseBody {
+            stream: stream,
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+        }
+    }
+
+    /// Set the keep-alive interval, `None` disables keep-alive comments.
+    pub fn keep_alive(mut self, interval: Option<Duration>) -> SseBody<S> {
+        self.keep_alive = interval;
+        return self;
+    }
+
+    pub fn into_body(self) -> Body {
+        let interval = self.keep_alive.map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        return Body::from_bytes_stream(EventStream {
+            stream: self.stream,
+            interval: interval,
+        });
+    }
+
+    /// Build a `200 OK` response with the headers required by event streams.
+    pub fn into_response(self) -> Result<Response<BoxBody>, anyhow::Error> {
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .header("x-accel-buffering", "no")
+            .body(self.into_body().into())?;
+        return Ok(response);
+    }
+}
+
+impl<S> From<SseBody<S>> for Body
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    fn from(body: SseBody<S>) -> Self {
+        body.into_body()
+    }
+}
+
+#[pin_project]
+struct EventStream<S> {
+    #[pin]
+    stream: S,
+    interval: Option<Interval>,
+}
+
+impl<S> Stream for EventStream<S>
+where
+    S: Stream<Item = Event>,
+{
+    type Item = Result<Bytes, Infallible>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        match this.stream.poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                if let Some(interval) = this.interval.as_mut() {
+                    interval.reset();
+                }
+                return Poll::Ready(Some(Ok(event.to_bytes())));
+            }
+            Poll::Ready(None) => {
+                return Poll::Ready(None);
+            }
+            Poll::Pending => {
+                if let Some(interval) = this.interval.as_mut() {
+                    if interval.poll_tick(cx).is_ready() {
+                        return Poll::Ready(Some(Ok(Bytes::from_static(b":\n\n"))));
+                    }
+                }
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_encode_event() {
+    let event = Event::new()
+        .id("1\n2")
+        .event("update")
+        .retry(Duration::from_secs(3))
+        .comment("hello\nworld")
+        .data("a\nb\r\nc\rd\n");
+    assert_eq!(
+        ":hello\n:world\nid: 12\nevent: update\nretry: 3000\ndata: a\ndata: b\ndata: c\ndata: d\ndata: \n\n",
+        String::from_utf8(event.to_bytes().to_vec()).unwrap()
+    );
+    assert_eq!(b"data: \n\n", &Event::new().data("").to_bytes()[..]);
+    assert_eq!(b"\n", &Event::new().to_bytes()[..]);
+}
+
+#[cfg(test)]
+#[tokio::test(start_paused = true)]
+async fn test_keep_alive() {
+    use futures::channel::mpsc;
+    use http_body_util::BodyExt;
+
+    let (sender, receiver) = mpsc::unbounded();
+    let mut body = SseBody::new(receiver)
+        .keep_alive(Some(Duration::from_secs(10)))
+        .into_body();
+    tokio::spawn(async move {
+        // Events sent faster than the keep-alive interval, then an idle source.
+        for i in 0..3 {
+            tokio::time::sleep(Duration::from_secs(6)).await;
+            sender
+                .unbounded_send(Event::new().data(i.to_string()))
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(25)).await;
+    });
+
+    let start = Instant::now();
+    let mut frames = Vec::new();
+    while let Some(frame) = body.frame().await {
+        let data = frame.unwrap().into_data().unwrap();
+        frames.push((
+            start.elapsed().as_secs(),
+            String::from_utf8(data.to_vec()).unwrap(),
+        ));
+    }
+    // The stream ends with the source.
+    assert_eq!(43, start.elapsed().as_secs());
+    assert_eq!(
+        vec![
+            (6, "data: 0\n\n".to_string()),
+            (12, "data: 1\n\n".to_string()),
+            (18, "data: 2\n\n".to_string()),
+            (28, ":\n\n".to_string()),
+            (38, ":\n\n".to_string()),
+        ],
+        frames
+    );
+}
@@ -0,0 +1,419 @@
+use super::router::prefix_len;
+use super::Body;
+use super::BoxBody;
+use super::HttpHandler;
+use super::RequestData;
+use async_trait::async_trait;
+use headers::{
+    AcceptRanges, ContentLength, ContentRange, ContentType, ETag, HeaderMapExt, IfModifiedSince,
+    IfNoneMatch, IfRange, LastModified, Range,
+};
+use hyper::header::{ACCEPT, ALLOW};
+use hyper::{Method, Request, Response, StatusCode};
+use mime_guess::mime;
+use percent_encoding::percent_decode_str;
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tihu::SharedString;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+const INDEX_FILE: &str = "index.html";
+
+/// Serve the files of a directory under the handler namespace.
+///
+/// Files are streamed, and `ETag`/`Last-Modified` conditional requests and single `Range`
+/// requests are supported. Paths escaping the directory, including through symbolic links,
+/// are not found. With the SPA fallback enabled, `index.html` is served for missing paths
+/// requested by a browser navigation.
+pub struct StaticFiles {
+    namespace: Vec<SharedString>,
+    root: PathBuf,
+    spa_fallback: bool,
+}
+
+fn status_response(status: StatusCode) -> Result<Response<BoxBody>, anyhow::Error> {
+    let response = Response::builder()
+        .status(status)
+        .body(Body::empty().into())?;
+    return Ok(response);
+}
+
+fn entity_tag(modified: SystemTime, len: u64) -> Option<ETag> {
+    let modified = modified.duration_since(UNIX_EPOCH).ok()?;
+    return format!("\"{:x}-{:x}\"", modified.as_nanos(), len)
+        .parse()
+        .ok();
+}
+
+/// The inclusive byte range to serve, `Err(())` when the range cannot be satisfied and
+/// `Ok(None)` when the whole file is served.
+fn byte_range(range: &Range, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let mut ranges = range.satisfiable_ranges(len);
+    let (start, end) = match (ranges.next(), ranges.next()) {
+        (Some(first), None) => first,
+        (None, _) => return Err(()),
+        // Multiple ranges are not supported, the whole file is served instead.
+        _ => return Ok(None),
+    };
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => end.min(len.saturating_sub(1)),
+        Bound::Excluded(end) => end.min(len).saturating_sub(1),
+        Bound::Unbounded => len.saturating_sub(1),
+    };
+    if len <= start || end < start {
+        return Err(());
+    }
+    return Ok(Some((start, end)));
+}
+
+impl StaticFiles {
+    pub fn new<P>(namespace: Vec<SharedString>, root: P) -> StaticFiles
+    where
+        P: Into<PathBuf>,
+    {
+        StaticFiles {
+            namespace: namespace,
+            root: root.into(),
+            spa_fallback: false,
+        }
+    }
+
+    /// Serve `index.html` of the directory for missing paths requested by browser
+    /// navigations, for single page applications with client side routing.
+    pub fn spa_fallback(mut self, spa_fallback: bool) -> StaticFiles {
+        self.spa_fallback = spa_fallback;
+        return self;
+    }
+
+    /// Find the file at `path`, or the index file if it is a directory, making sure it is
+    /// inside the root directory.
+    async fn find_file(&self, path: &Path) -> Option<(PathBuf, Metadata)> {
+        let mut path = tokio::fs::canonicalize(path).await.ok()?;
+        let mut metadata = tokio::fs::metadata(&path).await.ok()?;
+        if metadata.is_dir() {
+            // The index file may be a symbolic link too, so the final path is checked.
+            path = tokio::fs::canonicalize(path.join(INDEX_FILE)).await.ok()?;
+            metadata = tokio::fs::metadata(&path).await.ok()?;
+        }
+        let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+        if !path.starts_with(&root) {
+            return None;
+        }
+        if metadata.is_file() {
+            return Some((path, metadata));
+        } else {
+            return None;
+        }
+    }
+
+    async fn resolve(&self, relative: &str, navigation: bool) -> Option<(PathBuf, Metadata)> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = percent_decode_str(segment).decode_utf8().ok()?;
+            if ".." == segment || "." == segment || segment.contains(['\\', '\0', ':']) {
+                return None;
+            }
+            path.push(segment.as_ref());
+        }
+        match self.find_file(&path).await {
+            Some(file) => return Some(file),
+            None if self.spa_fallback && navigation => {
+                return self.find_file(&self.root.join(INDEX_FILE)).await;
+            }
+            None => return None,
+        }
+    }
+}
+
+#[async_trait]
+impl HttpHandler for StaticFiles {
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        request: Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+        prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let method = request.method();
+        if Method::GET != method && Method::HEAD != method {
+            let mut response = status_response(StatusCode::METHOD_NOT_ALLOWED)?;
+            response.headers_mut().insert(ALLOW, "GET, HEAD".parse()?);
+            return Ok(response);
+        }
+        let headers = request.headers();
+        let path = request.uri().path();
+        let navigation = headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("text/html"));
+        let (file_path, metadata) = match self
+            .resolve(&path[prefix_len(path, prefix)..], navigation)
+            .await
+        {
+            Some(file) => file,
+            None => return status_response(StatusCode::NOT_FOUND),
+        };
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let etag = modified.and_then(|modified| entity_tag(modified, len));
+        let last_modified = modified.map(LastModified::from);
+
+        let mut response = status_response(StatusCode::OK)?;
+        let response_headers = response.headers_mut();
+        response_headers.typed_insert(AcceptRanges::bytes());
+        if let Some(etag) = etag.clone() {
+            response_headers.typed_insert(etag);
+        }
+        if let Some(last_modified) = last_modified {
+            response_headers.typed_insert(last_modified);
+        }
+
+        let not_modified = match headers.typed_get::<IfNoneMatch>() {
+            Some(if_none_match) => etag
+                .as_ref()
+                .is_some_and(|etag| !if_none_match.precondition_passes(etag)),
+            None => match (headers.typed_get::<IfModifiedSince>(), modified) {
+                (Some(if_modified_since), Some(modified)) => {
+                    !if_modified_since.is_modified(modified)
+                }
+                _ => false,
+            },
+        };
+        if not_modified {
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            return Ok(response);
+        }
+
+        let range = match headers.typed_get::<Range>() {
+            Some(range) => {
+                let fresh = headers.typed_get::<IfRange>().is_none_or(|if_range| {
+                    !if_range.is_modified(etag.as_ref(), last_modified.as_ref())
+                });
+                if fresh {
+                    match byte_range(&range, len) {
+                        Ok(range) => range,
+                        Err(()) => {
+                            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                            response
+                                .headers_mut()
+                                .typed_insert(ContentRange::unsatisfied_bytes(len));
+                            return Ok(response);
+                        }
+                    }
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let mime = mime_guess::from_path(&file_path).first_or_octet_stream();
+        let content_type = if mime::TEXT == mime.type_() || mime::JAVASCRIPT == mime.subtype() {
+            format!("{}; charset=utf-8", mime.essence_str())
+        } else {
+            mime.essence_str().to_string()
+        };
+        let response_headers = response.headers_mut();
+        response_headers.typed_insert(ContentType::from(content_type.parse::<mime::Mime>()?));
+        let (start, length) = match range {
+            Some((start, end)) => {
+                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                response
+                    .headers_mut()
+                    .typed_insert(ContentRange::bytes(start..=end, len)?);
+                (start, end - start + 1)
+            }
+            None => (0, len),
+        };
+        response.headers_mut().typed_insert(ContentLength(length));
+        if Method::HEAD == method {
+            return Ok(response);
+        }
+        let mut file = tokio::fs::File::open(&file_path).await?;
+        if 0 < start {
+            file.seek(SeekFrom::Start(start)).await?;
+        }
+        let body = Body::from_bytes_stream(ReaderStream::new(file.take(length)));
+        let (parts, _) = response.into_parts();
+        return Ok(Response::from_parts(parts, body.into()));
+    }
+}
+
+#[test]
+fn test_byte_range() {
+    let cases = [
+        ("bytes=0-9", Ok(Some((0, 9)))),
+        ("bytes=10-", Ok(Some((10, 99)))),
+        ("bytes=-10", Ok(Some((90, 99)))),
+        ("bytes=90-200", Ok(Some((90, 99)))),
+        ("bytes=100-", Err(())),
+        ("bytes=0-1, 5-6", Ok(None)),
+    ];
+    for (value, expected) in cases {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(hyper::header::RANGE, value.parse().unwrap());
+        let range = headers.typed_get::<Range>().unwrap();
+        assert_eq!(expected, byte_range(&range, 100), "{}", value);
+    }
+}
+
+#[cfg(all(test, unix))]
+#[tokio::test]
+async fn test_static_files() {
+    use super::{Router, TestRequest};
+    use hyper::header::{
+        ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED, RANGE,
+    };
+    use std::os::unix::fs::symlink;
+
+    let base = std::env::temp_dir().join(format!(
+        "tihu-static-{}-{}",
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+    ));
+    let root = base.join("root");
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::write(base.join("secret.txt"), "secret").unwrap();
+    std::fs::write(root.join("index.html"), "<html></html>").unwrap();
+    std::fs::write(root.join("data.txt"), "0123456789").unwrap();
+    symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+    symlink(base.join("secret.txt"), root.join("docs").join(INDEX_FILE)).unwrap();
+
+    let mut router = Router::new();
+    router
+        .register(StaticFiles::new(
+            vec![SharedString::from_static("static")],
+            &root,
+        ))
+        .unwrap();
+    router
+        .register(
+            StaticFiles::new(vec![SharedString::from_static("app")], &root).spa_fallback(true),
+        )
+        .unwrap();
+
+    let response = TestRequest::get("/static/data.txt")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("0123456789", response.text().unwrap());
+    assert_eq!("10", response.headers[CONTENT_LENGTH]);
+    assert_eq!("bytes", response.headers[ACCEPT_RANGES]);
+    assert_eq!(
+        "text/plain; charset=utf-8",
+        response.headers[hyper::header::CONTENT_TYPE]
+    );
+    let etag = response.headers[ETAG].clone();
+    let last_modified = response.headers[LAST_MODIFIED].clone();
+    let response = TestRequest::get("/static/").send(&router).await.unwrap();
+    assert_eq!("<html></html>", response.text().unwrap());
+
+    // Paths escaping the root directory are not found.
+    for path in [
+        "/static/../secret.txt",
+        "/static/%2e%2e/secret.txt",
+        "/static/%2E%2E/secret.txt",
+        "/static/docs/..%2f..%2fsecret.txt",
+        "/static/link.txt",
+        "/static/docs",
+        "/static/missing.txt",
+    ] {
+        let response = TestRequest::get(path).send(&router).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status, "path: {}", path);
+        assert!(response.body.is_empty(), "path: {}", path);
+    }
+
+    let response = TestRequest::get("/static/data.txt")
+        .header(IF_NONE_MATCH, etag.clone())
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_MODIFIED, response.status);
+    assert!(response.body.is_empty());
+    let response = TestRequest::get("/static/data.txt")
+        .header(IF_NONE_MATCH, "\"other\"")
+        .header(IF_MODIFIED_SINCE, last_modified.clone())
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    let response = TestRequest::get("/static/data.txt")
+        .header(IF_MODIFIED_SINCE, last_modified)
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_MODIFIED, response.status);
+
+    let response = TestRequest::get("/static/data.txt")
+        .header(RANGE, "bytes=2-5")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::PARTIAL_CONTENT, response.status);
+    assert_eq!("2345", response.text().unwrap());
+    assert_eq!("bytes 2-5/10", response.headers[CONTENT_RANGE]);
+    assert_eq!("4", response.headers[CONTENT_LENGTH]);
+    let response = TestRequest::get("/static/data.txt")
+        .header(RANGE, "bytes=20-")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status);
+    assert_eq!("bytes */10", response.headers[CONTENT_RANGE]);
+    assert!(response.body.is_empty());
+
+    let response = TestRequest::new(Method::HEAD, "/static/data.txt")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("10", response.headers[CONTENT_LENGTH]);
+    assert!(response.body.is_empty());
+    let response = TestRequest::post("/static/data.txt")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::METHOD_NOT_ALLOWED, response.status);
+    assert_eq!("GET, HEAD", response.headers[ALLOW]);
+
+    // The index file is only served for missing paths of browser navigations.
+    let response = TestRequest::get("/app/user/1")
+        .header(ACCEPT, "text/html,application/xhtml+xml")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!("<html></html>", response.text().unwrap());
+    let response = TestRequest::get("/app/user/1")
+        .header(ACCEPT, "application/json")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status);
+    let response = TestRequest::get("/static/user/1")
+        .header(ACCEPT, "text/html")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::NOT_FOUND, response.status);
+
+    std::fs::remove_dir_all(&base).unwrap();
+}
@@ -0,0 +1,252 @@
+use super::read_body;
+use super::Body;
+use super::Format;
+use super::HttpHandler;
+use super::RequestData;
+use crate::xml::{parse_xml, Child};
+use crate::ErrNo;
+use bytes::Bytes;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE, HOST};
+use hyper::{HeaderMap, Method, Request, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// A request run through a handler in-process, for tests.
+///
+/// The request is handed to the handler as a `Request<Body>`, without any connection or
+/// socket opened. Each request gets a fresh [`RequestData`], and `127.0.0.1:0` as the remote
+/// address unless set.
+///
+/// Invalid headers or bodies are reported by [`TestRequest::send`].
+pub struct TestRequest {
+    builder: hyper::http::request::Builder,
+    body: Result<Body, anyhow::Error>,
+    remote_addr: SocketAddr,
+}
+
+impl TestRequest {
+    /// A request to `uri`, a path like `/api/user?id=1` or an absolute url.
+    pub fn new(method: Method, uri: &str) -> TestRequest {
+        TestRequest {
+            builder: Request::builder().method(method).uri(uri),
+            body: Ok(Body::empty()),
+            remote_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        }
+    }
+
+    pub fn get(uri: &str) -> TestRequest {
+        return TestRequest::new(Method::GET, uri);
+    }
+
+    pub fn post(uri: &str) -> TestRequest {
+        return TestRequest::new(Method::POST, uri);
+    }
+
+    pub fn header<K, V>(mut self, name: K, value: V) -> TestRequest
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: Into<hyper::http::Error>,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: Into<hyper::http::Error>,
+    {
+        self.builder = self.builder.header(name, value);
+        return self;
+    }
+
+    pub fn body<B>(mut self, body: B) -> TestRequest
+    where
+        B: Into<Body>,
+    {
+        self.body = Ok(body.into());
+        return self;
+    }
+
+    /// Set the body to `data` serialized in `format`, with the matching `Content-Type`.
+    pub fn encoded<T>(mut self, format: Format, data: &T) -> TestRequest
+    where
+        T: Serialize + ?Sized,
+    {
+        self.builder = self.builder.header(CONTENT_TYPE, format.content_type());
+        self.body = format.encode(data).map(Body::from);
+        return self;
+    }
+
+    /// Set the body to `data` serialized as JSON.
+    pub fn json<T>(self, data: &T) -> TestRequest
+    where
+        T: Serialize + ?Sized,
+    {
+        return self.encoded(Format::Json, data);
+    }
+
+    pub fn remote_addr(mut self, remote_addr: SocketAddr) -> TestRequest {
+        self.remote_addr = remote_addr;
+        return self;
+    }
+
+    /// Run the request through `handler`, a [`super::Router`] for example, and read the
+    /// whole response.
+    ///
+    /// Errors returned by the handler are returned as is, instead of the response.
+    pub async fn send<H>(self, handler: &H) -> Result<TestResponse, anyhow::Error>
+    where
+        H: HttpHandler,
+    {
+        let mut request = self.builder.body(self.body?)?;
+        if let Some(authority) = request.uri().authority().cloned() {
+            let host = HeaderValue::from_str(authority.as_str())?;
+            request.headers_mut().entry(HOST).or_insert(host);
+        }
+        let mut request_data = RequestData::new();
+        let response = handler
+            .handle(request, self.remote_addr, &mut request_data, None)
+            .await?;
+        let (parts, body) = response.into_parts();
+        let body = read_body(body).await?;
+        return Ok(TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: body,
+        });
+    }
+}
+
+/// A response read by [`TestRequest::send`].
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn text(&self) -> Result<&str, ErrNo> {
+        return std::str::from_utf8(&self.body).map_err(|_| ErrNo::Utf8Only);
+    }
+
+    pub fn json<T>(&self) -> Result<T, ErrNo>
+    where
+        T: DeserializeOwned,
+    {
+        return Format::Json.decode(&self.body);
+    }
+
+    /// Decode the body as a [`tihu::api::Response`], in the format of its `Content-Type`.
+    pub fn api_response<T>(&self) -> Result<tihu::api::Response<T>, ErrNo>
+    where
+        T: DeserializeOwned,
+    {
+        return Format::from_content_type(&self.headers).decode(&self.body);
+    }
+
+    /// Parse the body as XML, the root elements are returned.
+    pub fn xml(&self) -> Result<Vec<Child>, anyhow::Error> {
+        return parse_xml(&self.body[..]).map_err(|err| anyhow::anyhow!(err));
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_harness() {
+    use crate::http::{BoxBody, PathParams, Router};
+    use crate::ApiHandler;
+    use async_trait::async_trait;
+    use hyper::Response;
+    use tihu::Api;
+    use tihu::SharedString;
+
+    struct Add;
+
+    #[async_trait]
+    impl Api for Add {
+        type Input = (i64, i64);
+        type Output = i64;
+        fn namespace() -> SharedString {
+            SharedString::from_static("math/add")
+        }
+    }
+
+    struct Echo {
+        namespace: Vec<SharedString>,
+    }
+
+    #[async_trait]
+    impl HttpHandler for Echo {
+        fn namespace(&self) -> &[SharedString] {
+            &self.namespace
+        }
+        async fn handle(
+            &self,
+            request: Request<Body>,
+            remote_addr: SocketAddr,
+            request_data: &mut RequestData,
+            _prefix: Option<&str>,
+        ) -> Result<Response<BoxBody>, anyhow::Error> {
+            let PathParams(name) = request_data
+                .try_get::<PathParams<String>>(&request, remote_addr)
+                .await?
+                .clone();
+            if "fail" == name {
+                return Err(ErrNo::NotAllowed.into());
+            }
+            let host = request.headers().get(HOST).cloned();
+            let body = read_body(request.into_body()).await?;
+            let body = format!(
+                "{} {} {:?} {}",
+                name,
+                remote_addr,
+                host,
+                String::from_utf8_lossy(&body)
+            );
+            return Ok(Response::new(Body::from(body).into()));
+        }
+    }
+
+    let mut router = Router::new();
+    router
+        .register(Echo {
+            namespace: vec![SharedString::from_static("echo")],
+        })
+        .unwrap();
+    router
+        .register(ApiHandler::<Add, _>::new(|(a, b): (i64, i64)| async move {
+            Ok(a + b)
+        }))
+        .unwrap();
+
+    let response = TestRequest::post("http://example.com/echo/tihu")
+        .remote_addr("10.0.0.1:1234".parse().unwrap())
+        .body("hello")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(StatusCode::OK, response.status);
+    assert_eq!(
+        "tihu 10.0.0.1:1234 Some(\"example.com\") hello",
+        response.text().unwrap()
+    );
+    let err = TestRequest::get("/echo/fail")
+        .send(&router)
+        .await
+        .unwrap_err();
+    assert!(matches!(err.downcast::<ErrNo>(), Ok(ErrNo::NotAllowed)));
+
+    let response = TestRequest::post("/math/add")
+        .json(&(1, 2))
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(Some(3), response.api_response::<i64>().unwrap().data);
+    let response = TestRequest::post("/math/add")
+        .encoded(Format::MessagePack, &(2, 3))
+        .header("accept", "application/xml")
+        .send(&router)
+        .await
+        .unwrap();
+    assert_eq!(Some(5), response.api_response::<i64>().unwrap().data);
+    match &response.xml().unwrap()[..] {
+        [Child::Node(node)] => assert_eq!("response", node.name),
+        other => panic!("unexpected xml {:?}", other),
+    }
+}
@@ -0,0 +1,522 @@
+use super::Body;
+use super::BoxBody;
+use super::FromBody;
+use super::HttpHandler;
+use super::RequestData;
+use crate::ErrNo;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{Method, Request, Response, StatusCode, Version};
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tihu::protocol::{Decoder, Layer};
+use tihu::SharedString;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::WebSocketStream;
+
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+pub use tokio_tungstenite::tungstenite::Message;
+
+/// Size limit of the messages received by default, after reassembling their fragments.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 << 20;
+/// Size limit of the frames received by default.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 4 << 20;
+
+fn header_contains(
+    headers: &HeaderMap,
+    name: impl hyper::header::AsHeaderName,
+    token: &str,
+) -> bool {
+    return headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token));
+}
+
+/// A WebSocket upgrade request, extracted with [`RequestData::remove_or_get_body`] or
+/// [`FromBody`].
+///
+/// Only HTTP/1.1 upgrades are supported. Incoming fragmented messages are reassembled, and
+/// the connection fails when a message or frame exceeds its size limit.
+pub struct WebSocketUpgrade {
+    key: HeaderValue,
+    requested_protocols: Vec<String>,
+    protocols: Vec<SharedString>,
+    on_upgrade: OnUpgrade,
+    config: WebSocketConfig,
+}
+
+impl WebSocketUpgrade {
+    /// Check the handshake headers, and take the upgrade of `request`.
+    pub fn from_request(request: &mut Request<Body>) -> Result<WebSocketUpgrade, ErrNo> {
+        let invalid = || ErrNo::ParamInvalid(SharedString::from_static("需要WebSocket升级请求"));
+        let headers = request.headers();
+        let valid = Method::GET == request.method()
+            && Version::HTTP_11 == request.version()
+            && header_contains(headers, CONNECTION, "upgrade")
+            && header_contains(headers, UPGRADE, "websocket")
+            && headers
+                .get(SEC_WEBSOCKET_VERSION)
+                .is_some_and(|version| "13" == version);
+        if !valid {
+            return Err(invalid());
+        }
+        let key = headers
+            .get(SEC_WEBSOCKET_KEY)
+            .cloned()
+            .ok_or_else(invalid)?;
+        let requested_protocols = headers
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim().to_string())
+            .filter(|protocol| !protocol.is_empty())
+            .collect();
+        let on_upgrade = request
+            .extensions_mut()
+            .remove::<OnUpgrade>()
+            .ok_or_else(invalid)?;
+        return Ok(WebSocketUpgrade {
+            key: key,
+            requested_protocols: requested_protocols,
+            protocols: Vec::new(),
+            on_upgrade: on_upgrade,
+            config: WebSocketConfig::default()
+                .max_message_size(Some(DEFAULT_MAX_MESSAGE_SIZE))
+                .max_frame_size(Some(DEFAULT_MAX_FRAME_SIZE)),
+        });
+    }
+
+    /// Set the size limit of the messages received, `None` for no limit.
+    pub fn max_message_size(mut self, max_message_size: Option<usize>) -> WebSocketUpgrade {
+        self.config = self.config.max_message_size(max_message_size);
+        return self;
+    }
+
+    /// Set the size limit of the frames received, `None` for no limit.
+    pub fn max_frame_size(mut self, max_frame_size: Option<usize>) -> WebSocketUpgrade {
+        self.config = self.config.max_frame_size(max_frame_size);
+        return self;
+    }
+
+    /// Set the subprotocols supported, by order of preference. The first one also requested
+    /// by the client is selected.
+    pub fn protocols<I, P>(mut self, protocols: I) -> WebSocketUpgrade
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<SharedString>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        return self;
+    }
+
+    /// The subprotocol selected, if any.
+    pub fn selected_protocol(&self) -> Option<&SharedString> {
+        return self.protocols.iter().find(|protocol| {
+            self.requested_protocols
+                .iter()
+                .any(|requested| requested == protocol.as_ref())
+        });
+    }
+
+    /// Build the `101 Switching Protocols` response to return, `callback` is run with the
+    /// WebSocket once the response is sent.
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Result<Response<BoxBody>, anyhow::Error>
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, HeaderValue::from_static("upgrade"))
+            .header(UPGRADE, HeaderValue::from_static("websocket"))
+            .header(SEC_WEBSOCKET_ACCEPT, derive_accept_key(self.key.as_bytes()))
+            .body(Body::empty().into())?;
+        let protocol = self.selected_protocol().cloned();
+        if let Some(protocol) = protocol.as_ref() {
+            response
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(protocol)?);
+        }
+        let config = self.config;
+        let on_upgrade = self.on_upgrade;
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    log::debug!("WebSocket升级失败: {}", err);
+                    return;
+                }
+            };
+            let inner = WebSocketStream::from_raw_socket(
+                TokioIo::new(upgraded),
+                Role::Server,
+                Some(config),
+            )
+            .await;
+            callback(WebSocket {
+                inner: inner,
+                protocol: protocol,
+            })
+            .await;
+        });
+        return Ok(response);
+    }
+}
+
+#[async_trait]
+impl FromBody for WebSocketUpgrade {
+    async fn try_extract_body(
+        request: &mut Request<Body>,
+        _remote_addr: SocketAddr,
+        _request_data: &mut RequestData,
+    ) -> Result<Self, anyhow::Error> {
+        return Ok(WebSocketUpgrade::from_request(request)?);
+    }
+}
+
+/// An upgraded WebSocket connection, a stream of the messages received and a sink of the
+/// messages to send.
+///
+/// Pings are answered automatically, the pongs are sent along with the next message sent or
+/// while reading. Close frames are received as [`Message::Close`], and echoed automatically.
+pub struct WebSocket {
+    inner: WebSocketStream<TokioIo<Upgraded>>,
+    protocol: Option<SharedString>,
+}
+
+impl WebSocket {
+    /// The subprotocol selected during the handshake.
+    pub fn protocol(&self) -> Option<&SharedString> {
+        return self.protocol.as_ref();
+    }
+
+    /// Receive the next message, `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Result<Message, anyhow::Error>> {
+        return self.next().await;
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<(), anyhow::Error> {
+        return SinkExt::send(self, message).await;
+    }
+
+    /// Send a close frame, and wait for the client to close the connection.
+    pub async fn close(mut self, frame: Option<CloseFrame>) -> Result<(), anyhow::Error> {
+        self.inner.close(frame).await?;
+        while let Some(message) = self.inner.next().await {
+            message?;
+        }
+        return Ok(());
+    }
+
+    /// Carry the messages of a tihu [`Layer`], like
+    /// [`FramedLayer`](tihu::protocol::FramedLayer), over binary frames.
+    pub fn layered<L>(self) -> LayeredWebSocket<L>
+    where
+        L: Layer,
+    {
+        return LayeredWebSocket {
+            socket: self,
+            decoder: L::new_decoder(),
+            closed: false,
+            _layer: PhantomData,
+        };
+    }
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, anyhow::Error>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        return self
+            .inner
+            .poll_next_unpin(cx)
+            .map(|message| message.map(|message| message.map_err(anyhow::Error::from)));
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = anyhow::Error;
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return self.inner.poll_ready_unpin(cx).map_err(Into::into);
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        return self.inner.start_send_unpin(item).map_err(Into::into);
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return self.inner.poll_flush_unpin(cx).map_err(Into::into);
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return self.inner.poll_close_unpin(cx).map_err(Into::into);
+    }
+}
+
+/// A [`WebSocket`] carrying the messages of the tihu [`Layer`] `L` over binary frames.
+///
+/// A layer message may span several binary frames, and a binary frame may hold several
+/// layer messages. Text messages are rejected, control messages are handled as by
+/// [`WebSocket`].
+pub struct LayeredWebSocket<L>
+where
+    L: Layer,
+{
+    socket: WebSocket,
+    decoder: L::Decoder,
+    closed: bool,
+    _layer: PhantomData<fn() -> L>,
+}
+
+impl<L> LayeredWebSocket<L>
+where
+    L: Layer,
+{
+    pub fn into_inner(self) -> WebSocket {
+        return self.socket;
+    }
+}
+
+impl<L> Unpin for LayeredWebSocket<L> where L: Layer {}
+
+impl<L> Stream for LayeredWebSocket<L>
+where
+    L: Layer,
+{
+    type Item = Result<Bytes, anyhow::Error>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(message) = self.decoder.next() {
+                return Poll::Ready(Some(Ok(message)));
+            }
+            if self.closed {
+                return Poll::Ready(None);
+            }
+            match futures::ready!(self.socket.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(data))) => self.decoder.append(&data),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Some(Err(ErrNo::ParamInvalid(SharedString::from_static(
+                        "只接受二进制消息",
+                    ))
+                    .into())));
+                }
+                Some(Ok(Message::Close(_))) | None => self.closed = true,
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            }
+        }
+    }
+}
+
+impl<L> Sink<Bytes> for LayeredWebSocket<L>
+where
+    L: Layer,
+{
+    type Error = anyhow::Error;
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return self.socket.poll_ready_unpin(cx);
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<(), Self::Error> {
+        let mut buffer = Vec::with_capacity(item.len() + 10);
+        L::encode(&mut buffer, &item);
+        return self.socket.start_send_unpin(Message::Binary(buffer.into()));
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return self.socket.poll_flush_unpin(cx);
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return self.socket.poll_close_unpin(cx);
+    }
+}
+
+/// Serve WebSocket connections under `namespace`, running `callback` for each connection
+/// with the remote address and the [`RequestData`] of the upgrade request.
+///
+/// Requests not asking for an upgrade are responded [`ErrNo::ParamInvalid`].
+pub struct WebSocketHandler<F> {
+    namespace: Vec<SharedString>,
+    callback: Arc<F>,
+    protocols: Vec<SharedString>,
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+}
+
+impl<F, Fut> WebSocketHandler<F>
+where
+    F: Fn(WebSocket, SocketAddr, RequestData) -> Fut + Sync + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    pub fn new(namespace: Vec<SharedString>, callback: F) -> WebSocketHandler<F> {
+        WebSocketHandler {
+            namespace: namespace,
+            callback: Arc::new(callback),
+            protocols: Vec::new(),
+            max_message_size: Some(DEFAULT_MAX_MESSAGE_SIZE),
+            max_frame_size: Some(DEFAULT_MAX_FRAME_SIZE),
+        }
+    }
+
+    /// See [`WebSocketUpgrade::protocols`].
+    pub fn protocols<I, P>(mut self, protocols: I) -> WebSocketHandler<F>
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<SharedString>,
+    {
+        self.protocols = protocols.into_iter().map(Into::into).collect();
+        return self;
+    }
+
+    pub fn max_message_size(mut self, max_message_size: Option<usize>) -> WebSocketHandler<F> {
+        self.max_message_size = max_message_size;
+        return self;
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: Option<usize>) -> WebSocketHandler<F> {
+        self.max_frame_size = max_frame_size;
+        return self;
+    }
+}
+
+#[async_trait]
+impl<F, Fut> HttpHandler for WebSocketHandler<F>
+where
+    F: Fn(WebSocket, SocketAddr, RequestData) -> Fut + Sync + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn namespace(&self) -> &[SharedString] {
+        &self.namespace
+    }
+    async fn handle(
+        &self,
+        mut request: Request<Body>,
+        remote_addr: SocketAddr,
+        request_data: &mut RequestData,
+        _prefix: Option<&str>,
+    ) -> Result<Response<BoxBody>, anyhow::Error> {
+        let upgrade = match WebSocketUpgrade::from_request(&mut request) {
+            Ok(upgrade) => upgrade,
+            Err(err_no) => return super::err_no_response(err_no),
+        };
+        let upgrade = upgrade
+            .protocols(self.protocols.iter().cloned())
+            .max_message_size(self.max_message_size)
+            .max_frame_size(self.max_frame_size);
+        let request_data = std::mem::take(request_data);
+        let callback = self.callback.clone();
+        return upgrade.on_upgrade(move |socket| callback(socket, remote_addr, request_data));
+    }
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_websocket() {
+    use crate::http::{Router, Server};
+    use tihu::protocol::FramedLayer;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+
+    let mut router = Router::new();
+    let echo = WebSocketHandler::new(
+        vec![SharedString::from_static("echo")],
+        |mut socket: WebSocket, _, _| async move {
+            while let Some(Ok(message)) = socket.recv().await {
+                if (message.is_text() || message.is_binary()) && socket.send(message).await.is_err()
+                {
+                    break;
+                }
+            }
+        },
+    )
+    .protocols(["chat"])
+    .max_message_size(Some(16));
+    router.register(echo).unwrap();
+    let framed = WebSocketHandler::new(
+        vec![SharedString::from_static("framed")],
+        |socket: WebSocket, _, _| async move {
+            let mut socket = socket.layered::<FramedLayer>();
+            while let Some(Ok(message)) = socket.next().await {
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            }
+        },
+    );
+    router.register(framed).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(router).serve(listener, std::future::pending()));
+
+    let mut request = format!("ws://{}/echo", addr).into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("x, chat"));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut client, response) = tokio_tungstenite::client_async(request, stream)
+        .await
+        .unwrap();
+    assert_eq!("chat", response.headers()[SEC_WEBSOCKET_PROTOCOL]);
+    client.send(Message::text("hello")).await.unwrap();
+    assert_eq!(
+        Message::text("hello"),
+        client.next().await.unwrap().unwrap()
+    );
+    // A message fragmented into a text frame and a continuation frame is reassembled.
+    let first = Frame::message(&b"he"[..], OpCode::Data(Data::Text), false);
+    client.send(Message::Frame(first)).await.unwrap();
+    let last = Frame::message(&b"llo"[..], OpCode::Data(Data::Continue), true);
+    client.send(Message::Frame(last)).await.unwrap();
+    assert_eq!(
+        Message::text("hello"),
+        client.next().await.unwrap().unwrap()
+    );
+    // Messages over the size limit fail the connection.
+    client.send(Message::binary(vec![0u8; 17])).await.unwrap();
+    match client.next().await {
+        None | Some(Err(_)) => (),
+        Some(Ok(Message::Close(Some(frame)))) if CloseCode::Size == frame.code => (),
+        other => panic!("expected the connection to fail, got {:?}", other),
+    }
+
+    let request = format!("ws://{}/framed", addr);
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let (mut client, _) = tokio_tungstenite::client_async(request, stream)
+        .await
+        .unwrap();
+    // Two layer messages in one binary frame, the second split over two frames.
+    let mut buffer = Vec::new();
+    FramedLayer::encode(&mut buffer, b"one");
+    FramedLayer::encode(&mut buffer, b"two");
+    let rest = buffer.split_off(buffer.len() - 2);
+    client.send(Message::binary(buffer)).await.unwrap();
+    client.send(Message::binary(rest)).await.unwrap();
+    let mut decoder = FramedLayer::new_decoder();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        match client.next().await.unwrap().unwrap() {
+            Message::Binary(data) => decoder.append(&data),
+            message => panic!("unexpected message {:?}", message),
+        }
+        while let Some(message) = decoder.next() {
+            received.push(message);
+        }
+    }
+    assert_eq!(
+        vec![Bytes::from_static(b"one"), Bytes::from_static(b"two")],
+        received
+    );
+}
@@ -1,7 +1,9 @@
+pub mod api;
 pub mod errno;
 pub mod http;
 pub mod xml;
 pub use anyhow;
+pub use api::ApiHandler;
 pub use bytes;
 pub use errno::ErrNo;
 pub use hyper;
@@ -57,11 +57,154 @@ pub trait Api {
     }
 }
 
+/// An invalid field of the request, several of them can be reported in one response.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FieldError {
+    /// Path of the field in the input, like `user.emails[1]`.
+    pub path: SharedString,
+    /// Kind of the error, like `required`, `format` or `range`.
+    pub kind: SharedString,
+    pub message: SharedString,
+}
+
+impl FieldError {
+    pub fn new<P, K, M>(path: P, kind: K, message: M) -> FieldError
+    where
+        P: Into<SharedString>,
+        K: Into<SharedString>,
+        M: Into<SharedString>,
+    {
+        return FieldError {
+            path: path.into(),
+            kind: kind.into(),
+            message: message.into(),
+        };
+    }
+}
+
+/// Failure codes of [`Response`], so clients match on a variant instead of a number.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ErrCode {
+    LoginRequired,
+    CommonError,
+    Other,
+    ConfigError,
+    Timeout,
+    NoService,
+    ServiceBusy,
+    ServicePaused,
+    NoSuchApi,
+    SerializeError,
+    DeserializeError,
+    ApiError,
+    Utf8Only,
+    ParamFormatError,
+    ParamInvalid,
+    TokenInvalid,
+    NotAllowed,
+    TooFrequent,
+    MultipartRequired,
+    UndefinedEnumValue,
+    NoDbClient,
+    PrepareStatementError,
+    QueryError,
+    ExecuteError,
+    OpenTransactionError,
+    ExtractDataError,
+    CommitTransactionError,
+    NoCacheClient,
+    CacheOperationError,
+    PayloadTooLarge,
+    /// A code not known by this version, defined by the application for example.
+    Unknown(i32),
+}
+
+impl ErrCode {
+    pub fn code(&self) -> i32 {
+        return match *self {
+            ErrCode::LoginRequired => -1,
+            ErrCode::CommonError => -2,
+            ErrCode::Other => -3,
+            ErrCode::ConfigError => -4,
+            ErrCode::Timeout => -5,
+            ErrCode::NoService => -6,
+            ErrCode::ServiceBusy => -7,
+            ErrCode::ServicePaused => -8,
+            ErrCode::NoSuchApi => -9,
+            ErrCode::SerializeError => -10,
+            ErrCode::DeserializeError => -11,
+            ErrCode::ApiError => -12,
+            ErrCode::Utf8Only => -13,
+            ErrCode::ParamFormatError => -14,
+            ErrCode::ParamInvalid => -15,
+            ErrCode::TokenInvalid => -16,
+            ErrCode::NotAllowed => -17,
+            ErrCode::TooFrequent => -18,
+            ErrCode::MultipartRequired => -19,
+            ErrCode::UndefinedEnumValue => -20,
+            ErrCode::NoDbClient => -21,
+            ErrCode::PrepareStatementError => -22,
+            ErrCode::QueryError => -23,
+            ErrCode::ExecuteError => -24,
+            ErrCode::OpenTransactionError => -25,
+            ErrCode::ExtractDataError => -26,
+            ErrCode::CommitTransactionError => -27,
+            ErrCode::NoCacheClient => -28,
+            ErrCode::CacheOperationError => -29,
+            ErrCode::PayloadTooLarge => -30,
+            ErrCode::Unknown(code) => code,
+        };
+    }
+
+    /// The variant of a failure code, `None` for the success code `0`.
+    pub fn from_code(code: i32) -> Option<ErrCode> {
+        let err_code = match code {
+            0 => return None,
+            -1 => ErrCode::LoginRequired,
+            -2 => ErrCode::CommonError,
+            -3 => ErrCode::Other,
+            -4 => ErrCode::ConfigError,
+            -5 => ErrCode::Timeout,
+            -6 => ErrCode::NoService,
+            -7 => ErrCode::ServiceBusy,
+            -8 => ErrCode::ServicePaused,
+            -9 => ErrCode::NoSuchApi,
+            -10 => ErrCode::SerializeError,
+            -11 => ErrCode::DeserializeError,
+            -12 => ErrCode::ApiError,
+            -13 => ErrCode::Utf8Only,
+            -14 => ErrCode::ParamFormatError,
+            -15 => ErrCode::ParamInvalid,
+            -16 => ErrCode::TokenInvalid,
+            -17 => ErrCode::NotAllowed,
+            -18 => ErrCode::TooFrequent,
+            -19 => ErrCode::MultipartRequired,
+            -20 => ErrCode::UndefinedEnumValue,
+            -21 => ErrCode::NoDbClient,
+            -22 => ErrCode::PrepareStatementError,
+            -23 => ErrCode::QueryError,
+            -24 => ErrCode::ExecuteError,
+            -25 => ErrCode::OpenTransactionError,
+            -26 => ErrCode::ExtractDataError,
+            -27 => ErrCode::CommitTransactionError,
+            -28 => ErrCode::NoCacheClient,
+            -29 => ErrCode::CacheOperationError,
+            -30 => ErrCode::PayloadTooLarge,
+            _ => ErrCode::Unknown(code),
+        };
+        return Some(err_code);
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Response<T> {
     pub code: i32,
     pub data: Option<T>,
     pub message: SharedString,
+    /// Invalid fields of the request, set on [`ErrCode::ParamInvalid`] failures reporting
+    /// them one by one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub errors: Option<Vec<FieldError>>,
 }
 
 impl<T> Response<T> {
@@ -70,6 +213,7 @@ impl<T> Response<T> {
             code: 0,
             data: data,
             message: SharedString::from_static("success"),
+            errors: None,
         };
     }
     pub fn failure(mut code: i32, msg: SharedString, data: Option<T>) -> Response<T> {
@@ -80,10 +224,46 @@ impl<T> Response<T> {
             code: code,
             data: data,
             message: msg,
+            errors: None,
         };
     }
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Response<T> {
+        self.errors = Some(errors);
+        return self;
+    }
+    /// The typed failure code, `None` on success.
+    pub fn err_code(&self) -> Option<ErrCode> {
+        return ErrCode::from_code(self.code);
+    }
 }
 
 pub fn success() -> &'static [u8] {
     return b"{\"code\":0,\"message\":\"success\",\"data\":null}";
 }
+
+#[test]
+fn test_err_code() {
+    assert_eq!(None, ErrCode::from_code(0));
+    assert_eq!(Some(ErrCode::LoginRequired), ErrCode::from_code(-1));
+    assert_eq!(Some(ErrCode::PayloadTooLarge), ErrCode::from_code(-30));
+    assert_eq!(Some(ErrCode::Unknown(-1000)), ErrCode::from_code(-1000));
+    assert_eq!(Some(ErrCode::Unknown(7)), ErrCode::from_code(7));
+    assert_eq!(
+        Some(ErrCode::Unknown(i32::MIN)),
+        ErrCode::from_code(i32::MIN)
+    );
+    for code in -40..0 {
+        assert_eq!(code, ErrCode::from_code(code).unwrap().code());
+    }
+
+    let response = Response::<()>::failure(-15, SharedString::from_static("参数无效"), None)
+        .with_errors(vec![FieldError::new("name", "required", "不能为空")]);
+    let json = serde_json::to_string(&response).unwrap();
+    let response: Response<()> = serde_json::from_str(&json).unwrap();
+    assert_eq!(Some(ErrCode::ParamInvalid), response.err_code());
+    assert_eq!("name", response.errors.unwrap()[0].path.as_str());
+    let response: Response<()> =
+        serde_json::from_str("{\"code\":0,\"data\":null,\"message\":\"success\"}").unwrap();
+    assert_eq!(None, response.err_code());
+    assert!(response.errors.is_none());
+}